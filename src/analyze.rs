@@ -0,0 +1,242 @@
+// analysis dump mode
+//
+// `bril_forge [<pass>...] --analyze <analysis>` prints what an analysis concludes about every
+// function as json, instead of the program. every bb is listed by index into the function's
+// bbs along with its label, dataflow analyses also give the facts in and out of every bb and
+// instruction.
+
+use crate::ast::*;
+use crate::dataflow::DataflowResult;
+use crate::dom::*;
+use crate::passes::coalesce::*;
+use crate::passes::const_prop::*;
+use crate::passes::live::get_liveness;
+use crate::passes::loops::get_natural_loops;
+use crate::passes::pointer_analysis::*;
+use crate::passes::range::*;
+use crate::passes::reaching_defs::*;
+use serde_json::{json, Map, Value};
+use std::collections::HashSet;
+
+fn get_bb_json(bb_idx: usize, bb: &BasicBlock) -> Map<String, Value> {
+    let mut bb_json: Map<String, Value> = Map::new();
+    bb_json.insert("index".to_string(), json!(bb_idx));
+    bb_json.insert("label".to_string(), json!(bb.get_label()));
+    bb_json
+}
+
+fn get_sorted_json(items: &HashSet<impl Ord + Clone + serde::Serialize>) -> Value {
+    let mut items: Vec<_> = items.iter().cloned().collect();
+    items.sort();
+    json!(items)
+}
+
+// facts in and out of every bb and instruction
+fn get_dataflow_json<F>(
+    bbs: &[BasicBlock],
+    result: &DataflowResult<F>,
+    get_fact_json: impl Fn(&F) -> Value,
+) -> Value {
+    let mut bbs_json: Vec<Value> = Vec::new();
+    for (bb_idx, bb) in bbs.iter().enumerate() {
+        let mut instrs_json: Vec<Value> = Vec::new();
+        for (inst_idx, inst) in bb.instrs.iter().enumerate() {
+            instrs_json.push(json!({
+                "inst": inst,
+                "in": get_fact_json(&result.inst_in[bb_idx][inst_idx]),
+                "out": get_fact_json(&result.inst_out[bb_idx][inst_idx]),
+            }));
+        }
+        let mut bb_json = get_bb_json(bb_idx, bb);
+        bb_json.insert("in".to_string(), get_fact_json(&result.bb_in[bb_idx]));
+        bb_json.insert("out".to_string(), get_fact_json(&result.bb_out[bb_idx]));
+        bb_json.insert("instrs".to_string(), json!(instrs_json));
+        bbs_json.push(Value::Object(bb_json));
+    }
+    json!({ "bbs": bbs_json })
+}
+
+// live variables
+fn analyze_liveness(function: &Function) -> Value {
+    let bbs = function.get_basic_blocks();
+    let result = get_liveness(&bbs);
+    get_dataflow_json(&bbs, &result, get_sorted_json)
+}
+
+// constant values of variables, undefined ones are left out
+fn analyze_constants(function: &Function) -> Value {
+    let bbs = function.get_basic_blocks();
+    let result = get_constant_states(function, &bbs);
+    get_dataflow_json(&bbs, &result, |state: &ConstantState| {
+        let mut state_json: Map<String, Value> = Map::new();
+        for (var, value) in state.constant_values.iter() {
+            let value_json: Value = match value {
+                ConstantValue::Constant(constant) => constant.clone(),
+                ConstantValue::Overdefined => json!("overdefined"),
+            };
+            state_json.insert(var.clone(), value_json);
+        }
+        Value::Object(state_json)
+    })
+}
+
+// intervals of int variables, bools are 0 or 1
+fn analyze_ranges(function: &Function) -> Value {
+    let bbs = function.get_basic_blocks();
+    let result = get_ranges(function, &bbs);
+    get_dataflow_json(&bbs, &result, |state: &RangeState| {
+        let mut state_json: Map<String, Value> = Map::new();
+        for (var, interval) in state.ranges.iter() {
+            state_json.insert(var.clone(), json!([interval.lo, interval.hi]));
+        }
+        Value::Object(state_json)
+    })
+}
+
+// which vars are live at the same time, and how many distinct vars the function uses
+fn analyze_interference(function: &Function) -> Value {
+    let bbs = function.get_basic_blocks();
+    let graph: InterferenceGraph = get_interference_graph(function, &bbs);
+    let mut graph_json: Map<String, Value> = Map::new();
+    for (var, neighbors) in graph.edges.iter() {
+        graph_json.insert(var.clone(), get_sorted_json(neighbors));
+    }
+    json!({
+        "variables": get_variables(function).len(),
+        "interference": Value::Object(graph_json),
+    })
+}
+
+// definitions sorted in program order, each with the variable it defines. arguments are given by
+// their index into the function's args, instructions by their location
+fn get_definitions_json(reaching_defs: &ReachingDefinitions, defs: &HashSet<Definition>) -> Value {
+    let mut defs: Vec<Definition> = defs.iter().cloned().collect();
+    defs.sort();
+    let defs_json: Vec<Value> = defs
+        .iter()
+        .map(|def| match def {
+            Definition::Argument(arg_idx) => json!({
+                "var": reaching_defs.get_var(def),
+                "arg": arg_idx,
+            }),
+            Definition::Inst((bb_idx, inst_idx)) => json!({
+                "var": reaching_defs.get_var(def),
+                "bb": bb_idx,
+                "inst": inst_idx,
+            }),
+        })
+        .collect();
+    json!(defs_json)
+}
+
+// definitions reaching every instruction, and the end of every bb
+fn analyze_reaching_defs(function: &Function) -> Value {
+    let bbs = function.get_basic_blocks();
+    let reaching_defs = ReachingDefinitions::new(function, &bbs);
+
+    let mut bbs_json: Vec<Value> = Vec::new();
+    for (bb_idx, bb) in bbs.iter().enumerate() {
+        let mut instrs_json: Vec<Value> = Vec::new();
+        for (inst_idx, inst) in bb.instrs.iter().enumerate() {
+            let location = (bb_idx, inst_idx);
+            instrs_json.push(json!({
+                "inst": inst,
+                "in": get_definitions_json(&reaching_defs, reaching_defs.reaching_in(location)),
+                "out": get_definitions_json(&reaching_defs, reaching_defs.reaching_out(location)),
+            }));
+        }
+        let mut bb_json = get_bb_json(bb_idx, bb);
+        bb_json.insert(
+            "out".to_string(),
+            get_definitions_json(&reaching_defs, reaching_defs.reaching_bb_out(bb_idx)),
+        );
+        bb_json.insert("instrs".to_string(), json!(instrs_json));
+        bbs_json.push(Value::Object(bb_json));
+    }
+    json!({ "bbs": bbs_json })
+}
+
+// allocation sites every pointer may point to, sites are indices into the function's instrs
+fn analyze_points_to(function: &Function) -> Value {
+    let bbs = function.get_basic_blocks();
+    let result = get_points_to_graphs(function, &bbs);
+    get_dataflow_json(&bbs, &result, |graph: &PointsToGraph| {
+        let mut graph_json: Map<String, Value> = Map::new();
+        for (var, sites) in graph.iter() {
+            graph_json.insert(var.clone(), get_sorted_json(sites));
+        }
+        Value::Object(graph_json)
+    })
+}
+
+fn analyze_dominators(function: &Function) -> Value {
+    let bbs = function.get_basic_blocks();
+    let dom_context: DomContext = get_dom_context(&bbs);
+    let children: Vec<Vec<usize>> = dom_context.get_dom_tree_children();
+
+    let mut bbs_json: Vec<Value> = Vec::new();
+    for (bb_idx, bb) in bbs.iter().enumerate() {
+        let bb_dom_context: &BBDomContext = &dom_context.bbs[bb_idx];
+        let mut bb_json = get_bb_json(bb_idx, bb);
+        bb_json.insert(
+            "dominators".to_string(),
+            get_sorted_json(&bb_dom_context.dominators),
+        );
+        bb_json.insert(
+            "immediate_dominator".to_string(),
+            json!(bb_dom_context.immediate_dominator),
+        );
+        bb_json.insert("dom_tree_children".to_string(), json!(children[bb_idx]));
+        bbs_json.push(Value::Object(bb_json));
+    }
+    json!({ "bbs": bbs_json })
+}
+
+// natural loops, one per back edge
+fn analyze_loops(function: &Function) -> Value {
+    let bbs = function.get_basic_blocks();
+    let bbs_json: Vec<Value> = bbs
+        .iter()
+        .enumerate()
+        .map(|(bb_idx, bb)| Value::Object(get_bb_json(bb_idx, bb)))
+        .collect();
+
+    let mut loops_json: Vec<Value> = Vec::new();
+    for loop_ in get_natural_loops(&bbs) {
+        let mut body: HashSet<usize> = loop_.nodes.iter().cloned().collect();
+        body.insert(loop_.header_idx);
+        body.insert(loop_.back_node_idx);
+        loops_json.push(json!({
+            "header": loop_.header_idx,
+            "back_node": loop_.back_node_idx,
+            "body": get_sorted_json(&body),
+        }));
+    }
+    json!({ "bbs": bbs_json, "loops": loops_json })
+}
+
+// the per-function report of the analysis with the given name
+pub fn get_analysis(name: &str) -> Option<fn(&Function) -> Value> {
+    match name {
+        "liveness" => Some(analyze_liveness),
+        "dominators" => Some(analyze_dominators),
+        "loops" => Some(analyze_loops),
+        "points-to" => Some(analyze_points_to),
+        "constants" => Some(analyze_constants),
+        "ranges" => Some(analyze_ranges),
+        "reaching-defs" => Some(analyze_reaching_defs),
+        "interference" => Some(analyze_interference),
+        _ => None,
+    }
+}
+
+// run the analysis over every function, as json
+pub fn analyze_program(program: &Program, analysis: fn(&Function) -> Value) -> Value {
+    let mut functions_json: Vec<Value> = Vec::new();
+    for function in program.functions.iter() {
+        let mut function_json: Value = analysis(function);
+        function_json["name"] = json!(function.name);
+        functions_json.push(function_json);
+    }
+    json!({ "functions": functions_json })
+}
@@ -41,6 +41,50 @@ pub struct BasicBlock {
     pub out_bb_indices: HashSet<usize>, // indices into the function's bb that this bb jumps out to
 }
 
+impl BasicBlock {
+    // label other bbs use to jump in to this bb, if any
+    pub fn get_label(&self) -> Option<String> {
+        match self.instrs.first() {
+            Some(Instruction::Label { label }) => Some(label.clone()),
+            _ => None,
+        }
+    }
+}
+
+// hands out variable/label names that don't collide with anything already in a function
+pub struct FreshNameGenerator {
+    taken: HashSet<String>,
+    counter: usize,
+}
+
+impl FreshNameGenerator {
+    pub fn new(function: &Function) -> FreshNameGenerator {
+        let mut taken: HashSet<String> = HashSet::new();
+        if let Some(args) = &function.args {
+            for arg in args.iter() {
+                taken.insert(arg.name.clone());
+            }
+        }
+        for inst in function.instrs.iter() {
+            // use list also covers labels that are jumped to
+            taken.extend(inst.get_use_list());
+            if let Some(result) = inst.get_result() {
+                taken.insert(result);
+            }
+        }
+        FreshNameGenerator { taken, counter: 0 }
+    }
+
+    pub fn fresh(&mut self, prefix: &str) -> String {
+        loop {
+            let name = format!("{}.{}", prefix, self.counter);
+            self.counter += 1;
+            if self.taken.insert(name.clone()) {
+                return name;
+            }
+        }
+    }
+}
 
 // innter representation of bb, ocntains labels for bb indexing
 #[derive(Debug)]
@@ -150,6 +194,10 @@ impl Function {
             }
         }
         if !current_block.instrs.is_empty() {
+            // the last bb can be jumped to as well
+            if let Some(label) = current_block.label.clone() {
+                bb_labels_to_indices.insert(label, bbs.len());
+            }
             bbs.push(current_block);
         }
 
@@ -203,7 +251,7 @@ pub struct Argument {
     pub arg_type: Type,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 #[serde(untagged)]
 pub enum Type {
     Primitive(String),
@@ -419,8 +467,19 @@ pub enum OpcodeInstruction {
     Jmp {
         labels: Vec<String>,
     },
+    // ssa extension: `dest` takes the arg whose label is the predecessor we came from
+    Phi {
+        args: Vec<String>,
+        dest: String,
+        labels: Vec<String>,
+        #[serde(rename = "type")]
+        typ: Type,
+    },
 }
 
+// bril's `to_ssa` uses this for phi args that are undefined along an edge
+pub const UNDEFINED_VAR: &str = "__undefined";
+
 impl Instruction {
     // jmp, br
     pub fn is_control_inst(&self) -> bool {
@@ -476,6 +535,18 @@ impl Instruction {
         }
     }
 
+    // like `get_use_list`, but only variables; labels jumped to are left out
+    pub fn get_var_use_list(&self) -> Vec<String> {
+        match self {
+            Instruction::Opcode(Inst) => Inst.get_var_use_list(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn is_phi(&self) -> bool {
+        matches!(self, Instruction::Opcode(OpcodeInstruction::Phi { .. }))
+    }
+
     pub fn get_result(&self) -> Option<String> {
         match self {
             Instruction::Opcode(Inst) => Inst.get_dest(),
@@ -486,6 +557,46 @@ impl Instruction {
 }
 
 impl OpcodeInstruction {
+    // the `op` field of the instruction in bril's json
+    pub fn get_opcode_name(&self) -> &'static str {
+        match self {
+            OpcodeInstruction::Const { .. } => "const",
+            OpcodeInstruction::Alloc { .. } => "alloc",
+            OpcodeInstruction::Call { .. } => "call",
+            OpcodeInstruction::Print { .. } => "print",
+            OpcodeInstruction::Free { .. } => "free",
+            OpcodeInstruction::Ret { .. } => "ret",
+            OpcodeInstruction::Id { .. } => "id",
+            OpcodeInstruction::Store { .. } => "store",
+            OpcodeInstruction::Ptradd { .. } => "ptradd",
+            OpcodeInstruction::Br { .. } => "br",
+            OpcodeInstruction::Or { .. } => "or",
+            OpcodeInstruction::Add { .. } => "add",
+            OpcodeInstruction::Sub { .. } => "sub",
+            OpcodeInstruction::Div { .. } => "div",
+            OpcodeInstruction::Mul { .. } => "mul",
+            OpcodeInstruction::FAdd { .. } => "fadd",
+            OpcodeInstruction::FSub { .. } => "fsub",
+            OpcodeInstruction::FDiv { .. } => "fdiv",
+            OpcodeInstruction::FMul { .. } => "fmul",
+            OpcodeInstruction::Eq { .. } => "eq",
+            OpcodeInstruction::Gt { .. } => "gt",
+            OpcodeInstruction::Ge { .. } => "ge",
+            OpcodeInstruction::Lt { .. } => "lt",
+            OpcodeInstruction::Le { .. } => "le",
+            OpcodeInstruction::FEq { .. } => "feq",
+            OpcodeInstruction::FGt { .. } => "fgt",
+            OpcodeInstruction::FGe { .. } => "fge",
+            OpcodeInstruction::FLt { .. } => "flt",
+            OpcodeInstruction::FLe { .. } => "fle",
+            OpcodeInstruction::And { .. } => "and",
+            OpcodeInstruction::Not { .. } => "not",
+            OpcodeInstruction::Load { .. } => "load",
+            OpcodeInstruction::Jmp { .. } => "jmp",
+            OpcodeInstruction::Phi { .. } => "phi",
+        }
+    }
+
     // whether the instruction is asssigning some value on rhs expr
     // to lhs
     pub fn is_assignment_inst(&self) -> bool {
@@ -527,6 +638,7 @@ impl OpcodeInstruction {
             OpcodeInstruction::Not { typ, .. } => Some(typ.clone()),
             OpcodeInstruction::Load { typ, .. } => Some(typ.clone()),
             OpcodeInstruction::Jmp { .. } => None,
+            OpcodeInstruction::Phi { typ, .. } => Some(typ.clone()),
         }
     }
     pub fn get_dest(&self) -> Option<String> {
@@ -556,7 +668,8 @@ impl OpcodeInstruction {
             | OpcodeInstruction::FLe { dest, .. }
             | OpcodeInstruction::And { dest, .. }
             | OpcodeInstruction::Not { dest, .. }
-            | OpcodeInstruction::Load { dest, .. } => Some(dest.clone()),
+            | OpcodeInstruction::Load { dest, .. }
+            | OpcodeInstruction::Phi { dest, .. } => Some(dest.clone()),
 
             OpcodeInstruction::Print { .. }
             | OpcodeInstruction::Free { .. }
@@ -612,9 +725,69 @@ impl OpcodeInstruction {
             OpcodeInstruction::Not { args, .. } => args.to_vec(),
             OpcodeInstruction::Load { args, .. } => args.to_vec(),
             OpcodeInstruction::Jmp { labels } => labels.to_vec(),
+            // phi labels name predecessors, they are not jumped to
+            OpcodeInstruction::Phi { args, .. } => args.to_vec(),
         }
     }
 
+    // mutable reference to the variable the instruction assigns to
+    pub fn get_dest_mut(&mut self) -> Option<&mut String> {
+        match self {
+            OpcodeInstruction::Const { dest, .. }
+            | OpcodeInstruction::Alloc { dest, .. }
+            | OpcodeInstruction::Id { dest, .. }
+            | OpcodeInstruction::Ptradd { dest, .. }
+            | OpcodeInstruction::Or { dest, .. }
+            | OpcodeInstruction::Add { dest, .. }
+            | OpcodeInstruction::Sub { dest, .. }
+            | OpcodeInstruction::Div { dest, .. }
+            | OpcodeInstruction::Mul { dest, .. }
+            | OpcodeInstruction::FAdd { dest, .. }
+            | OpcodeInstruction::FSub { dest, .. }
+            | OpcodeInstruction::FDiv { dest, .. }
+            | OpcodeInstruction::FMul { dest, .. }
+            | OpcodeInstruction::Eq { dest, .. }
+            | OpcodeInstruction::Gt { dest, .. }
+            | OpcodeInstruction::Ge { dest, .. }
+            | OpcodeInstruction::Lt { dest, .. }
+            | OpcodeInstruction::Le { dest, .. }
+            | OpcodeInstruction::FEq { dest, .. }
+            | OpcodeInstruction::FGt { dest, .. }
+            | OpcodeInstruction::FGe { dest, .. }
+            | OpcodeInstruction::FLt { dest, .. }
+            | OpcodeInstruction::FLe { dest, .. }
+            | OpcodeInstruction::And { dest, .. }
+            | OpcodeInstruction::Not { dest, .. }
+            | OpcodeInstruction::Load { dest, .. }
+            | OpcodeInstruction::Phi { dest, .. } => Some(dest),
+            OpcodeInstruction::Call { dest, .. } => dest.as_mut(),
+            _ => None,
+        }
+    }
+
+    pub fn get_var_use_list(&self) -> Vec<String> {
+        match self {
+            OpcodeInstruction::Br { args, .. } => args.to_vec(),
+            OpcodeInstruction::Jmp { .. } => Vec::new(),
+            _ => self.get_use_list(),
+        }
+    }
+
+    // whether swapping the two args gives the same result
+    pub fn is_commutative(&self) -> bool {
+        matches!(
+            self,
+            OpcodeInstruction::Add { .. }
+                | OpcodeInstruction::Mul { .. }
+                | OpcodeInstruction::FAdd { .. }
+                | OpcodeInstruction::FMul { .. }
+                | OpcodeInstruction::Eq { .. }
+                | OpcodeInstruction::FEq { .. }
+                | OpcodeInstruction::And { .. }
+                | OpcodeInstruction::Or { .. }
+        )
+    }
+
     // returns mutable reference to function arguments
     pub fn get_args(&mut self) -> Option<&mut Vec<String>> {
         match self {
@@ -650,7 +823,8 @@ impl OpcodeInstruction {
             | OpcodeInstruction::FLe { args, .. }
             | OpcodeInstruction::And { args, .. }
             | OpcodeInstruction::Not { args, .. }
-            | OpcodeInstruction::Load { args, .. } => Some(args),
+            | OpcodeInstruction::Load { args, .. }
+            | OpcodeInstruction::Phi { args, .. } => Some(args),
 
             // Handle the Call variant separately when `args` is None
             OpcodeInstruction::Call { args: None, .. } => None,
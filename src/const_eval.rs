@@ -0,0 +1,61 @@
+// constant evaluation of bril's pure core opcodes
+//
+// follows the reference interpreter: ints are 64 bits and wrap around, division truncates
+// toward zero, floats are ieee doubles. anything the program would trip over at runtime, like
+// dividing by zero, isn't folded so the program still trips over it.
+
+use crate::ast::*;
+use serde_json::Value;
+
+fn fold_int(args: &[Value], op: fn(i64, i64) -> Option<Value>) -> Option<Value> {
+    op(args.first()?.as_i64()?, args.get(1)?.as_i64()?)
+}
+
+fn fold_float(args: &[Value], op: fn(f64, f64) -> Value) -> Option<Value> {
+    let value = op(args.first()?.as_f64()?, args.get(1)?.as_f64()?);
+    // nan and infinities have no json representation
+    match value.as_f64() {
+        Some(float) if !float.is_finite() => None,
+        _ => Some(value),
+    }
+}
+
+fn fold_bool(args: &[Value], op: fn(bool, bool) -> bool) -> Option<Value> {
+    Some(op(args.first()?.as_bool()?, args.get(1)?.as_bool()?).into())
+}
+
+// evaluate a pure instruction on constant args, `None` if it can't be folded. args line up
+// with the instruction's variable uses.
+pub fn fold(opcode_inst: &OpcodeInstruction, args: &[Value]) -> Option<Value> {
+    match opcode_inst {
+        OpcodeInstruction::Id { .. } => args.first().cloned(),
+        OpcodeInstruction::Add { .. } => fold_int(args, |a, b| Some(a.wrapping_add(b).into())),
+        OpcodeInstruction::Sub { .. } => fold_int(args, |a, b| Some(a.wrapping_sub(b).into())),
+        OpcodeInstruction::Mul { .. } => fold_int(args, |a, b| Some(a.wrapping_mul(b).into())),
+        OpcodeInstruction::Div { .. } => {
+            // division by zero is a runtime error, leave it for the program to hit
+            fold_int(args, |a, b| match b {
+                0 => None,
+                _ => Some(a.wrapping_div(b).into()),
+            })
+        }
+        OpcodeInstruction::Eq { .. } => fold_int(args, |a, b| Some((a == b).into())),
+        OpcodeInstruction::Lt { .. } => fold_int(args, |a, b| Some((a < b).into())),
+        OpcodeInstruction::Gt { .. } => fold_int(args, |a, b| Some((a > b).into())),
+        OpcodeInstruction::Le { .. } => fold_int(args, |a, b| Some((a <= b).into())),
+        OpcodeInstruction::Ge { .. } => fold_int(args, |a, b| Some((a >= b).into())),
+        OpcodeInstruction::FAdd { .. } => fold_float(args, |a, b| (a + b).into()),
+        OpcodeInstruction::FSub { .. } => fold_float(args, |a, b| (a - b).into()),
+        OpcodeInstruction::FMul { .. } => fold_float(args, |a, b| (a * b).into()),
+        OpcodeInstruction::FDiv { .. } => fold_float(args, |a, b| (a / b).into()),
+        OpcodeInstruction::FEq { .. } => fold_float(args, |a, b| (a == b).into()),
+        OpcodeInstruction::FLt { .. } => fold_float(args, |a, b| (a < b).into()),
+        OpcodeInstruction::FGt { .. } => fold_float(args, |a, b| (a > b).into()),
+        OpcodeInstruction::FLe { .. } => fold_float(args, |a, b| (a <= b).into()),
+        OpcodeInstruction::FGe { .. } => fold_float(args, |a, b| (a >= b).into()),
+        OpcodeInstruction::And { .. } => fold_bool(args, |a, b| a && b),
+        OpcodeInstruction::Or { .. } => fold_bool(args, |a, b| a || b),
+        OpcodeInstruction::Not { .. } => Some((!args.first()?.as_bool()?).into()),
+        _ => None,
+    }
+}
@@ -0,0 +1,192 @@
+// generic dataflow analysis over a function's bbs
+//
+// an analysis describes its lattice (bottom, join, equality through `PartialEq`), which way
+// facts flow and how instructions transform them, `solve` runs the worklist to a fixpoint and
+// hands back the facts at every bb and instruction boundary.
+
+use crate::ast::*;
+use crate::dom::get_successors;
+use std::collections::{HashSet, VecDeque};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Forward,  // facts flow from the entry bb along cfg edges
+    Backward, // facts flow from the exit bbs against cfg edges
+}
+
+// (bb index, instruction index in the bb)
+pub type InstLocation = (usize, usize);
+
+pub trait Analysis {
+    type Fact: Clone + PartialEq;
+
+    fn direction(&self) -> Direction;
+
+    // what every bb starts out with before anything flows into it
+    fn bottom(&self) -> Self::Fact;
+
+    // what flows into the entry bb (forward) or out of the exit bbs (backward)
+    fn boundary(&self) -> Self::Fact;
+
+    // combine facts where control flow merges
+    fn join(&self, a: &Self::Fact, b: &Self::Fact) -> Self::Fact;
+
+    // update `fact` across `inst`, in the direction of the analysis
+    fn transfer_inst(&self, fact: &mut Self::Fact, inst: &Instruction, location: InstLocation);
+
+    // refine `fact` for the cfg edge `from` -> `to` it flows along, e.g. with what the
+    // branch taking the edge says about its condition. does nothing by default.
+    fn transfer_edge(&self, _fact: &mut Self::Fact, _bbs: &[BasicBlock], _from: usize, _to: usize) {
+    }
+
+    // what a bb takes as its input when `next` comes in after `previous` did, analyses whose
+    // facts can grow forever jump ahead here to make sure they settle. takes `next` as is by
+    // default.
+    fn widen(&self, _previous: &Self::Fact, next: Self::Fact, _bb_idx: usize) -> Self::Fact {
+        next
+    }
+
+    // update `fact` across a whole bb, by default one instruction at a time.
+    // per-instruction facts are always derived from `transfer_inst`.
+    fn transfer_bb(&self, fact: &mut Self::Fact, bb: &BasicBlock, bb_idx: usize) {
+        match self.direction() {
+            Direction::Forward => {
+                for (inst_idx, inst) in bb.instrs.iter().enumerate() {
+                    self.transfer_inst(fact, inst, (bb_idx, inst_idx));
+                }
+            }
+            Direction::Backward => {
+                for (inst_idx, inst) in bb.instrs.iter().enumerate().rev() {
+                    self.transfer_inst(fact, inst, (bb_idx, inst_idx));
+                }
+            }
+        }
+    }
+}
+
+// facts at the fixpoint. "in" is always the top of a bb / instruction and "out" the bottom,
+// whichever way the analysis goes
+pub struct DataflowResult<F> {
+    pub bb_in: Vec<F>,
+    pub bb_out: Vec<F>,
+    pub inst_in: Vec<Vec<F>>,
+    pub inst_out: Vec<Vec<F>>,
+}
+
+pub fn solve<A: Analysis>(analysis: &A, bbs: &[BasicBlock]) -> DataflowResult<A::Fact> {
+    let forward: bool = analysis.direction() == Direction::Forward;
+
+    let succs: Vec<Vec<usize>> = get_successors(bbs);
+    let mut preds: Vec<Vec<usize>> = vec![Vec::new(); bbs.len()];
+    for (bb_idx, bb_succs) in succs.iter().enumerate() {
+        for succ_idx in bb_succs.iter() {
+            preds[*succ_idx].push(bb_idx);
+        }
+    }
+    // where facts come from and go to, in the direction of the analysis
+    let (sources, targets) = if forward {
+        (&preds, &succs)
+    } else {
+        (&succs, &preds)
+    };
+
+    // facts going into / out of every bb, in the direction of the analysis
+    let mut inputs: Vec<A::Fact> = vec![analysis.bottom(); bbs.len()];
+    let mut outputs: Vec<A::Fact> = vec![analysis.bottom(); bbs.len()];
+
+    let mut work_list: VecDeque<usize> = VecDeque::new();
+    let mut in_work_list: HashSet<usize> = HashSet::new();
+    let ordering: Vec<usize> = if forward {
+        (0..bbs.len()).collect()
+    } else {
+        (0..bbs.len()).rev().collect()
+    };
+    for bb_idx in ordering {
+        work_list.push_back(bb_idx);
+        in_work_list.insert(bb_idx);
+    }
+
+    while let Some(bb_idx) = work_list.pop_front() {
+        in_work_list.remove(&bb_idx);
+
+        let is_boundary: bool = if forward {
+            bb_idx == 0
+        } else {
+            succs[bb_idx].is_empty()
+        };
+        let mut input: Option<A::Fact> = if is_boundary {
+            Some(analysis.boundary())
+        } else {
+            None
+        };
+        for source_idx in sources[bb_idx].iter() {
+            let mut edge_fact: A::Fact = outputs[*source_idx].clone();
+            let (from, to) = if forward {
+                (*source_idx, bb_idx)
+            } else {
+                (bb_idx, *source_idx)
+            };
+            analysis.transfer_edge(&mut edge_fact, bbs, from, to);
+            input = Some(match input {
+                Some(input) => analysis.join(&input, &edge_fact),
+                None => edge_fact,
+            });
+        }
+        let input: A::Fact = input.unwrap_or_else(|| analysis.bottom());
+        let input: A::Fact = analysis.widen(&inputs[bb_idx], input, bb_idx);
+
+        let mut output: A::Fact = input.clone();
+        analysis.transfer_bb(&mut output, &bbs[bb_idx], bb_idx);
+        inputs[bb_idx] = input;
+
+        if output != outputs[bb_idx] {
+            outputs[bb_idx] = output;
+            for target_idx in targets[bb_idx].iter() {
+                if in_work_list.insert(*target_idx) {
+                    work_list.push_back(*target_idx);
+                }
+            }
+        }
+    }
+
+    // replay every bb from its input to get the per-instruction facts
+    let mut inst_inputs: Vec<Vec<A::Fact>> = Vec::new();
+    let mut inst_outputs: Vec<Vec<A::Fact>> = Vec::new();
+    for (bb_idx, bb) in bbs.iter().enumerate() {
+        let mut fact: A::Fact = inputs[bb_idx].clone();
+        let mut bb_inst_inputs: Vec<A::Fact> = Vec::new();
+        let mut bb_inst_outputs: Vec<A::Fact> = Vec::new();
+        let inst_indices: Vec<usize> = if forward {
+            (0..bb.instrs.len()).collect()
+        } else {
+            (0..bb.instrs.len()).rev().collect()
+        };
+        for inst_idx in inst_indices {
+            bb_inst_inputs.push(fact.clone());
+            analysis.transfer_inst(&mut fact, &bb.instrs[inst_idx], (bb_idx, inst_idx));
+            bb_inst_outputs.push(fact.clone());
+        }
+        if !forward {
+            bb_inst_inputs.reverse();
+            bb_inst_outputs.reverse();
+        }
+        inst_inputs.push(bb_inst_inputs);
+        inst_outputs.push(bb_inst_outputs);
+    }
+
+    if forward {
+        DataflowResult {
+            bb_in: inputs,
+            bb_out: outputs,
+            inst_in: inst_inputs,
+            inst_out: inst_outputs,
+        }
+    } else {
+        DataflowResult {
+            bb_in: outputs,
+            bb_out: inputs,
+            inst_in: inst_outputs,
+            inst_out: inst_inputs,
+        }
+    }
+}
@@ -6,6 +6,7 @@ use std::collections::HashSet;
 // dominance info of a basic block
 pub struct BBDomContext {
     pub dominators: HashSet<usize>, // indices to BB that dominates this BB
+    pub immediate_dominator: Option<usize>, // parent in the dominator tree, none for the root
 }
 
 // dominance context of a function's basic blocks
@@ -13,14 +14,29 @@ pub struct DomContext {
     pub bbs: Vec<BBDomContext>,
 }
 
-fn get_post_order_traversal_ordering(bbs: &Vec<BasicBlock>) -> Vec<usize> {
+impl DomContext {
+    // children of every bb in the dominator tree, in ascending order
+    pub fn get_dom_tree_children(&self) -> Vec<Vec<usize>> {
+        let mut children: Vec<Vec<usize>> = vec![Vec::new(); self.bbs.len()];
+        for (bb_idx, bb_ctx) in self.bbs.iter().enumerate() {
+            if let Some(idom) = bb_ctx.immediate_dominator {
+                children[idom].push(bb_idx);
+            }
+        }
+        children
+    }
+}
+
+fn get_post_order_traversal_ordering(bbs: &[BasicBlock]) -> Vec<usize> {
     fn visit(
         bb_idx: usize,
-        bbs: &Vec<BasicBlock>,
+        bbs: &[BasicBlock],
         ordering: &mut Vec<usize>,
         visited: &mut HashSet<usize>,
     ) {
         assert!(!visited.contains(&bb_idx)); // mut have been not visited before
+        // mark as visited before going into children, loops lead back here
+        visited.insert(bb_idx);
 
         let bb: &BasicBlock;
         unsafe {
@@ -36,9 +52,6 @@ fn get_post_order_traversal_ordering(bbs: &Vec<BasicBlock>) -> Vec<usize> {
 
         // finally add self to ordering
         ordering.push(bb_idx);
-
-        // mark as visited
-        visited.insert(bb_idx);
     }
 
     let mut ordering: Vec<usize> = Vec::new();
@@ -51,7 +64,7 @@ fn get_post_order_traversal_ordering(bbs: &Vec<BasicBlock>) -> Vec<usize> {
     ordering
 }
 
-fn get_reverse_post_order_traversal_ordering(bbs: &Vec<BasicBlock>) -> Vec<usize> {
+fn get_reverse_post_order_traversal_ordering(bbs: &[BasicBlock]) -> Vec<usize> {
     let mut ret = get_post_order_traversal_ordering(bbs);
     ret.reverse();
     ret
@@ -73,53 +86,197 @@ fn get_set_intersection(sets: &Vec<HashSet<usize>>) -> HashSet<usize> {
 }
 
 // get the dominance context of the function's bbs using reverse post-order traversal.
-pub fn get_dom_context(bbs: &Vec<BasicBlock>) -> DomContext {
+// bbs unreachable from the entry bb end up with no dominators at all.
+pub fn get_dom_context(bbs: &[BasicBlock]) -> DomContext {
     let mut ctx: DomContext = DomContext { bbs: Vec::new() };
 
     // initialize ctx with empty data
     for _i in 0..bbs.len() {
         ctx.bbs.push(BBDomContext {
             dominators: HashSet::new(),
+            immediate_dominator: None,
         })
     }
 
-    let reverse_post_ordering = get_reverse_post_order_traversal_ordering(&bbs);
+    let reverse_post_ordering = get_reverse_post_order_traversal_ordering(bbs);
 
-    // visit the bbs in reverse post order, calculating dom context for each bb
+    // reachable bbs start out dominated by every reachable bb, the intersections below only
+    // ever shrink that
+    let reachable: HashSet<usize> = reverse_post_ordering.iter().cloned().collect();
     for bb_idx in reverse_post_ordering.iter() {
-        let bb: &BasicBlock;
-        unsafe {
-            bb = bbs.get_unchecked(bb_idx.clone());
-        }
+        ctx.bbs[*bb_idx].dominators = reachable.clone();
+    }
 
-        // in (bb) = and(out(parent) for all parent in parent(bb) + bb
+    // visit the bbs in reverse post order, calculating dom context for each bb, until nothing
+    // changes. back edges mean a parent can be visited after its child.
+    let mut changed: bool = true;
+    while changed {
+        changed = false;
+        for bb_idx in reverse_post_ordering.iter() {
+            let bb: &BasicBlock = &bbs[*bb_idx];
 
-        let mut all_parent_dominators: Vec<HashSet<usize>> = Vec::new();
+            // in (bb) = and(out(parent) for all parent in parent(bb) + bb
 
-        // take intersection of all parents' dominators
+            let mut all_parent_dominators: Vec<HashSet<usize>> = Vec::new();
 
-        for parent_bb_idx in bb.in_bb_indices.iter() {
-            let parent_bb_ctx;
-            unsafe {
-                parent_bb_ctx = ctx.bbs.get_unchecked_mut(parent_bb_idx.clone());
+            // take intersection of all reachable parents' dominators, the entry bb has none
+            if *bb_idx != 0 {
+                for parent_bb_idx in bb.in_bb_indices.iter() {
+                    if reachable.contains(parent_bb_idx) {
+                        all_parent_dominators.push(ctx.bbs[*parent_bb_idx].dominators.clone());
+                    }
+                }
             }
 
-            all_parent_dominators.push(parent_bb_ctx.dominators.clone());
+            let mut dominators: HashSet<usize> = get_set_intersection(&all_parent_dominators);
+            // bb dominates itself
+            dominators.insert(*bb_idx);
+
+            if dominators != ctx.bbs[*bb_idx].dominators {
+                changed = true;
+                ctx.bbs[*bb_idx].dominators = dominators;
+            }
         }
+    }
 
-        let parent_dominators_itersection: HashSet<usize> =
-            get_set_intersection(&all_parent_dominators);
+    // dominators of a bb form a chain, the closest one is dominated by all the others
+    for bb_idx in reverse_post_ordering.iter() {
+        let bb_dominators = &ctx.bbs[*bb_idx].dominators;
+        ctx.bbs[*bb_idx].immediate_dominator = bb_dominators
+            .iter()
+            .filter(|dominator| *dominator != bb_idx)
+            .find(|dominator| ctx.bbs[**dominator].dominators.len() == bb_dominators.len() - 1)
+            .cloned();
+    }
 
-        let bb_dom_ctx: &mut BBDomContext;
-        unsafe {
-            bb_dom_ctx = ctx.bbs.get_unchecked_mut(bb_idx.clone());
+    ctx
+}
+
+// successors of every bb as far as control actually goes, a bb containing `ret` never falls
+// through to the next one even though the cfg says so
+pub fn get_successors(bbs: &[BasicBlock]) -> Vec<Vec<usize>> {
+    bbs.iter()
+        .map(|bb| {
+            let returns: bool = bb
+                .instrs
+                .iter()
+                .any(|inst| matches!(inst, Instruction::Opcode(OpcodeInstruction::Ret { .. })));
+            if returns {
+                Vec::new()
+            } else {
+                let mut succs: Vec<usize> = bb.out_bb_indices.iter().cloned().collect();
+                succs.sort();
+                succs
+            }
+        })
+        .collect()
+}
+
+// bbs control leaves the function from, i.e. bbs without successors. a loop that never exits
+// has no such bb, one of its bbs stands in as an exit so every bb ends up post-dominated by
+// something.
+pub fn get_exit_bbs(bbs: &[BasicBlock]) -> Vec<usize> {
+    let succs = get_successors(bbs);
+    let mut preds: Vec<Vec<usize>> = vec![Vec::new(); bbs.len()];
+    for (bb_idx, bb_succs) in succs.iter().enumerate() {
+        for succ_idx in bb_succs.iter() {
+            preds[*succ_idx].push(bb_idx);
         }
+    }
+
+    let mut exits: Vec<usize> = (0..bbs.len())
+        .filter(|bb_idx| succs[*bb_idx].is_empty())
+        .collect();
 
-        // shared dominators of parents dominate the child
-        bb_dom_ctx.dominators = parent_dominators_itersection;
-        // bb dominates itself
-        bb_dom_ctx.dominators.insert(bb_idx.clone());
+    // everything reaching an exit, walking the cfg backwards
+    let mut reaches_exit: HashSet<usize> = HashSet::new();
+    let mut work_list: Vec<usize> = exits.clone();
+    loop {
+        while let Some(bb_idx) = work_list.pop() {
+            if reaches_exit.insert(bb_idx) {
+                work_list.extend(preds[bb_idx].iter().cloned());
+            }
+        }
+        // the last bb of a loop is usually where it jumps back, good enough as its exit
+        match (0..bbs.len()).rev().find(|bb_idx| !reaches_exit.contains(bb_idx)) {
+            Some(bb_idx) => {
+                exits.push(bb_idx);
+                work_list.push(bb_idx);
+            }
+            None => break,
+        }
     }
 
-    ctx
+    exits
+}
+
+// post-dominance context of the function's bbs, which is dominance on the reversed cfg
+// entered from a virtual exit that every bb in `get_exit_bbs` flows into.
+// `immediate_dominator` is the immediate post-dominator, none means the virtual exit.
+pub fn get_post_dom_context(bbs: &[BasicBlock]) -> DomContext {
+    let succs = get_successors(bbs);
+
+    // the reversed cfg, virtual exit at index 0 and every bb shifted up by one
+    let mut reversed_bbs: Vec<BasicBlock> = (0..=bbs.len())
+        .map(|_| BasicBlock {
+            instrs: Vec::new(),
+            in_bb_indices: HashSet::new(),
+            out_bb_indices: HashSet::new(),
+        })
+        .collect();
+    let mut add_edge = |from: usize, to: usize| {
+        reversed_bbs[from].out_bb_indices.insert(to);
+        reversed_bbs[to].in_bb_indices.insert(from);
+    };
+    for (bb_idx, bb_succs) in succs.iter().enumerate() {
+        for succ_idx in bb_succs.iter() {
+            add_edge(succ_idx + 1, bb_idx + 1);
+        }
+    }
+    for exit_idx in get_exit_bbs(bbs) {
+        add_edge(0, exit_idx + 1);
+    }
+
+    let reversed_ctx = get_dom_context(&reversed_bbs);
+    DomContext {
+        bbs: reversed_ctx
+            .bbs
+            .into_iter()
+            .skip(1)
+            .map(|bb_ctx| BBDomContext {
+                dominators: bb_ctx
+                    .dominators
+                    .into_iter()
+                    .filter(|dominator| *dominator != 0)
+                    .map(|dominator| dominator - 1)
+                    .collect(),
+                immediate_dominator: bb_ctx
+                    .immediate_dominator
+                    .filter(|dominator| *dominator != 0)
+                    .map(|dominator| dominator - 1),
+            })
+            .collect(),
+    }
+}
+
+// for every bb, the bbs whose branch decides whether it runs: bb is control dependent on x
+// when x has a successor bb post-dominates but bb doesn't strictly post-dominate x
+pub fn get_control_dependences(
+    bbs: &[BasicBlock],
+    post_dom_context: &DomContext,
+) -> Vec<HashSet<usize>> {
+    let mut control_dependences: Vec<HashSet<usize>> = vec![HashSet::new(); bbs.len()];
+    for (bb_idx, bb_succs) in get_successors(bbs).iter().enumerate() {
+        let ipdom = post_dom_context.bbs[bb_idx].immediate_dominator;
+        for succ_idx in bb_succs.iter() {
+            // everything from the successor up to (excluding) bb's immediate post-dominator
+            let mut runner: Option<usize> = Some(*succ_idx);
+            while runner.is_some() && runner != ipdom {
+                let runner_idx = runner.unwrap();
+                control_dependences[runner_idx].insert(bb_idx);
+                runner = post_dom_context.bbs[runner_idx].immediate_dominator;
+            }
+        }
+    }
+    control_dependences
 }
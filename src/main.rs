@@ -1,8 +1,12 @@
 use std::collections::HashMap;
 use std::env;
+mod analyze;
 mod ast;
+mod const_eval;
 mod dom;
+mod dataflow;
 mod passes;
+mod scoped_table;
 use ast::*; // dispatch table definition
 
 macro_rules! create_pass_map {
@@ -18,17 +22,27 @@ macro_rules! create_pass_map {
 }
 
 //
-// bril_forge <pass name>...
+// bril_forge <pass name>... [--analyze <analysis name>]
 //
 fn main() {
     // construct dispatch table
+    use passes::adce::aggressive_dce_pass;
+    use passes::coalesce::coalesce_pass;
     use passes::const_prop::*;
+    use passes::copy_prop::copy_propagation_pass;
+    use passes::cse::global_cse_pass;
     use passes::dce::*;
     use passes::example::*;
+    use passes::gvn::gvn_pass;
+    use passes::lcm::lazy_code_motion_pass;
     use passes::lvn::*;
     use passes::live::global_dce_pass_using_livenss;
     use passes::loops::*;
+    use passes::out_of_ssa::out_of_ssa_pass;
     use passes::pointer_analysis::pointer_analysis_pass;
+    use passes::range::value_range_pass;
+    use passes::sccp::sccp_pass;
+    use passes::ssa_verifier::verify_ssa_pass;
 
 
     let dispatch_table: HashMap<&str, fn(&mut Program) -> bool> = create_pass_map!(
@@ -38,12 +52,23 @@ fn main() {
         // dce passes
         naive_dce_pass,
         local_dce_pass,
+        aggressive_dce_pass,
         // lvn pass
         lvn_pass,
+        gvn_pass,
+        global_cse_pass,
+        lazy_code_motion_pass,
         global_const_propagation_pass,
+        sccp_pass,
+        value_range_pass,
+        copy_propagation_pass,
         global_dce_pass_using_livenss,
         loop_invariant_code_motion_pass,
-        pointer_analysis_pass
+        pointer_analysis_pass,
+        coalesce_pass,
+        // ssa
+        out_of_ssa_pass,
+        verify_ssa_pass
     );
 
     // read program
@@ -52,7 +77,14 @@ fn main() {
     // program.dump();
     // println!("after: \n");
     // dispatch passes as specified from stdin
-    for arg in env::args().skip(1) {
+    let mut args = env::args().skip(1);
+    let mut analysis_name: Option<String> = None;
+    while let Some(arg) = args.next() {
+        // dump an analysis of the program as it is after the passes, instead of the program
+        if arg == "--analyze" {
+            analysis_name = Some(args.next().expect("--analyze needs an analysis name"));
+            continue;
+        }
         let pass = dispatch_table.get(&*arg);
         match pass {
             Some(pass) => {
@@ -67,5 +99,11 @@ fn main() {
 
     // program.dump();
 
-    program.dump_json(); // json is piped out to the output
+    match analysis_name {
+        Some(analysis_name) => match analyze::get_analysis(&analysis_name) {
+            Some(analysis) => println!("{}", analyze::analyze_program(&program, analysis)),
+            None => panic!("analysis {} does not exist.", analysis_name),
+        },
+        None => program.dump_json(), // json is piped out to the output
+    }
 }
@@ -0,0 +1,235 @@
+// aggressive (mark-and-sweep) dce
+//
+// the other dce passes keep anything that's used, so values only feeding each other (a loop
+// counter nobody reads, a whole useless loop) never go away. here nothing is live unless an
+// essential instruction needs it: start from instructions with effects, mark the definitions
+// they read and the branches deciding whether they run, then delete everything unmarked.
+// branches that decide nothing anymore become jumps to the next bb that still does something.
+use crate::ast;
+use crate::dataflow::InstLocation;
+use crate::dom;
+use crate::passes::reaching_defs::*;
+use ast::*;
+use dom::*;
+use std::collections::{HashMap, HashSet};
+
+fn is_essential(inst: &Instruction) -> bool {
+    match inst {
+        Instruction::Opcode(opcode_inst) => matches!(
+            opcode_inst,
+            OpcodeInstruction::Print { .. }
+                | OpcodeInstruction::Store { .. }
+                | OpcodeInstruction::Ret { .. }
+                | OpcodeInstruction::Free { .. }
+                // we don't know what the callee does
+                | OpcodeInstruction::Call { .. }
+        ),
+        _ => false,
+    }
+}
+
+// the `br` / `jmp` ending the bb, if any
+fn get_terminator(bb: &BasicBlock) -> Option<usize> {
+    bb.instrs.iter().rposition(|inst| inst.is_control_inst())
+}
+
+struct MarkState<'a> {
+    bbs: &'a Vec<BasicBlock>,
+    control_dependences: Vec<HashSet<usize>>,
+    label_to_bb: HashMap<String, usize>,
+    reaching_defs: ReachingDefinitions,
+    marked: HashSet<InstLocation>,
+    live_bbs: HashSet<usize>,
+    work_list: Vec<InstLocation>,
+}
+
+impl<'a> MarkState<'a> {
+    fn mark(&mut self, location: InstLocation) {
+        if self.marked.insert(location) {
+            self.work_list.push(location);
+        }
+    }
+
+    // the bb has to run, so do the branches deciding whether it does
+    fn mark_bb_live(&mut self, bb_idx: usize) {
+        if !self.live_bbs.insert(bb_idx) {
+            return;
+        }
+        let deciding_bbs: Vec<usize> = self.control_dependences[bb_idx].iter().cloned().collect();
+        for deciding_idx in deciding_bbs {
+            if let Some(inst_idx) = get_terminator(&self.bbs[deciding_idx]) {
+                self.mark((deciding_idx, inst_idx));
+            }
+        }
+    }
+
+    // mark the definitions of `var` that can reach instruction `inst_idx` of bb `bb_idx`,
+    // an `inst_idx` past the last instruction means the end of the bb
+    fn mark_reaching_defs(&mut self, var: &str, bb_idx: usize, inst_idx: usize) {
+        let defs: Vec<Definition> = if inst_idx < self.bbs[bb_idx].instrs.len() {
+            self.reaching_defs
+                .get_reaching_defs(var, (bb_idx, inst_idx))
+        } else {
+            self.reaching_defs.get_reaching_defs_at_bb_end(var, bb_idx)
+        };
+        for def in defs {
+            // function arguments are always there
+            if let Definition::Inst(location) = def {
+                self.mark(location);
+            }
+        }
+    }
+
+    fn process(&mut self, (bb_idx, inst_idx): InstLocation) {
+        self.mark_bb_live(bb_idx);
+
+        let inst: &Instruction = &self.bbs[bb_idx].instrs[inst_idx];
+        match inst {
+            Instruction::Opcode(OpcodeInstruction::Phi { args, labels, .. }) => {
+                // an arg is read at the end of its predecessor, which also has to get here
+                let incoming: Vec<(String, String)> =
+                    args.iter().cloned().zip(labels.iter().cloned()).collect();
+                for (arg, label) in incoming {
+                    if let Some(pred_idx) = self.label_to_bb.get(&label).cloned() {
+                        let pred_len = self.bbs[pred_idx].instrs.len();
+                        self.mark_reaching_defs(&arg, pred_idx, pred_len);
+                        match get_terminator(&self.bbs[pred_idx]) {
+                            Some(terminator_idx) => self.mark((pred_idx, terminator_idx)),
+                            None => self.mark_bb_live(pred_idx),
+                        }
+                    }
+                }
+            }
+            _ => {
+                for var in inst.get_var_use_list() {
+                    self.mark_reaching_defs(&var, bb_idx, inst_idx);
+                }
+            }
+        }
+    }
+}
+
+fn adce_fn(function: &mut Function) -> bool {
+    let mut bbs = function.get_basic_blocks();
+    let post_dom_context = get_post_dom_context(&bbs);
+
+    let mut label_to_bb: HashMap<String, usize> = HashMap::new();
+    for (bb_idx, bb) in bbs.iter().enumerate() {
+        if let Some(label) = bb.get_label() {
+            label_to_bb.insert(label, bb_idx);
+        }
+    }
+
+    let mut state = MarkState {
+        bbs: &bbs,
+        control_dependences: get_control_dependences(&bbs, &post_dom_context),
+        label_to_bb,
+        reaching_defs: ReachingDefinitions::new(function, &bbs),
+        marked: HashSet::new(),
+        live_bbs: HashSet::new(),
+        work_list: Vec::new(),
+    };
+
+    for (bb_idx, bb) in bbs.iter().enumerate() {
+        for (inst_idx, inst) in bb.instrs.iter().enumerate() {
+            if is_essential(inst) {
+                state.mark((bb_idx, inst_idx));
+            }
+        }
+    }
+    // a loop that never exits has to keep looping
+    for exit_idx in get_exit_bbs(&bbs) {
+        match get_terminator(&bbs[exit_idx]) {
+            Some(inst_idx) => state.mark((exit_idx, inst_idx)),
+            None => state.mark_bb_live(exit_idx),
+        }
+    }
+
+    while let Some(location) = state.work_list.pop() {
+        state.process(location);
+    }
+
+    let marked: HashSet<InstLocation> = state.marked;
+    let live_bbs: HashSet<usize> = state.live_bbs;
+
+    // where an unmarked branch should go instead: the closest post-dominator still doing
+    // anything, or out of the function if there's none
+    let mut fresh_names = FreshNameGenerator::new(function);
+    let mut new_labels: HashMap<usize, String> = HashMap::new();
+    let mut new_terminators: Vec<Option<Instruction>> = vec![None; bbs.len()];
+    for bb_idx in 0..bbs.len() {
+        let dead_branch: bool = bbs[bb_idx]
+            .instrs
+            .iter()
+            .enumerate()
+            .any(|(inst_idx, inst)| {
+                matches!(inst, Instruction::Opcode(OpcodeInstruction::Br { .. }))
+                    && !marked.contains(&(bb_idx, inst_idx))
+            });
+        if !dead_branch {
+            continue;
+        }
+        let mut new_target: Option<usize> = post_dom_context.bbs[bb_idx].immediate_dominator;
+        while let Some(target_idx) = new_target {
+            if live_bbs.contains(&target_idx) {
+                break;
+            }
+            new_target = post_dom_context.bbs[target_idx].immediate_dominator;
+        }
+        new_terminators[bb_idx] = Some(match new_target {
+            Some(target_idx) => {
+                // only ever fallen into so far, needs a label to be jumped to
+                let label: String = match bbs[target_idx].get_label() {
+                    Some(label) => label,
+                    None => new_labels
+                        .entry(target_idx)
+                        .or_insert_with(|| fresh_names.fresh("adce"))
+                        .clone(),
+                };
+                Instruction::Opcode(OpcodeInstruction::Jmp {
+                    labels: vec![label],
+                })
+            }
+            None => Instruction::Opcode(OpcodeInstruction::Ret { args: Vec::new() }),
+        });
+    }
+
+    // sweep
+    let mut changed: bool = !new_labels.is_empty();
+    for (bb_idx, bb) in bbs.iter_mut().enumerate() {
+        let old_instrs: Vec<Instruction> = std::mem::take(&mut bb.instrs);
+        if let Some(label) = new_labels.get(&bb_idx) {
+            bb.instrs.push(Instruction::Label {
+                label: label.clone(),
+            });
+        }
+        for (inst_idx, inst) in old_instrs.into_iter().enumerate() {
+            let keep: bool = marked.contains(&(bb_idx, inst_idx))
+                || inst.is_label()
+                || matches!(inst, Instruction::Opcode(OpcodeInstruction::Jmp { .. }));
+            if keep {
+                bb.instrs.push(inst);
+                continue;
+            }
+            if let (Instruction::Opcode(OpcodeInstruction::Br { .. }), Some(new_terminator)) =
+                (&inst, &new_terminators[bb_idx])
+            {
+                bb.instrs.push(new_terminator.clone());
+            }
+            changed = true;
+        }
+    }
+
+    if changed {
+        function.update(bbs);
+    }
+    changed
+}
+
+pub fn aggressive_dce_pass(program: &mut Program) -> bool {
+    let mut changed: bool = false;
+    for function in program.functions.iter_mut() {
+        changed |= adce_fn(function);
+    }
+    changed
+}
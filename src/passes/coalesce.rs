@@ -0,0 +1,261 @@
+// variable coalescing
+//
+// two variables interfere when one is written while the other is still live. variables
+// that never interfere and have the same type can share one name, like registers handed out
+// by a graph coloring allocator. a copy `x = id y` doesn't make `x` interfere with `y`, they
+// hold the same value, so coalescing them turns the copy into `x = id x`, which is dropped.
+use crate::ast;
+use crate::dataflow::DataflowResult;
+use crate::passes::live::*;
+use ast::*;
+use std::collections::{HashMap, HashSet};
+
+pub struct InterferenceGraph {
+    pub vars: Vec<String>, // in order of first appearance, args first
+    pub types: HashMap<String, Type>,
+    pub edges: HashMap<String, HashSet<String>>,
+}
+
+impl InterferenceGraph {
+    fn add_var(&mut self, var: &str) {
+        if !self.edges.contains_key(var) {
+            self.vars.push(var.to_string());
+            self.edges.insert(var.to_string(), HashSet::new());
+        }
+    }
+
+    fn add_edge(&mut self, a: &str, b: &str) {
+        if a == b {
+            return;
+        }
+        self.add_var(a);
+        self.add_var(b);
+        self.edges.get_mut(a).unwrap().insert(b.to_string());
+        self.edges.get_mut(b).unwrap().insert(a.to_string());
+    }
+
+    pub fn interferes(&self, a: &str, b: &str) -> bool {
+        self.edges
+            .get(a)
+            .is_some_and(|neighbors| neighbors.contains(b))
+    }
+}
+
+// every distinct variable the function names: args, dests and uses
+pub fn get_variables(function: &Function) -> HashSet<String> {
+    let mut vars: HashSet<String> = HashSet::new();
+    if let Some(args) = &function.args {
+        vars.extend(args.iter().map(|arg| arg.name.clone()));
+    }
+    for inst in function.instrs.iter() {
+        if let Instruction::Opcode(opcode_inst) = inst {
+            vars.extend(opcode_inst.get_dest());
+            vars.extend(opcode_inst.get_var_use_list());
+        }
+    }
+    vars
+}
+
+pub fn get_interference_graph(function: &Function, bbs: &[BasicBlock]) -> InterferenceGraph {
+    let mut graph = InterferenceGraph {
+        vars: Vec::new(),
+        types: HashMap::new(),
+        edges: HashMap::new(),
+    };
+    let live: DataflowResult<HashSet<String>> = get_liveness(bbs);
+
+    // args, and vars read before any write, all hold their values at the same time on entry
+    let mut entry: Vec<String> = Vec::new();
+    if let Some(args) = &function.args {
+        for arg in args.iter() {
+            graph.add_var(&arg.name);
+            graph.types.insert(arg.name.clone(), arg.arg_type.clone());
+            entry.push(arg.name.clone());
+        }
+    }
+    if let Some(live_in) = live.bb_in.first() {
+        let mut live_in: Vec<String> = live_in.iter().cloned().collect();
+        live_in.sort();
+        for var in live_in {
+            if !entry.contains(&var) {
+                graph.add_var(&var);
+                entry.push(var);
+            }
+        }
+    }
+    for a in entry.iter() {
+        for b in entry.iter() {
+            graph.add_edge(a, b);
+        }
+    }
+
+    // a var written with two different types can't be given anyone else's name
+    let mut mixed: HashSet<String> = HashSet::new();
+    for (bb_idx, bb) in bbs.iter().enumerate() {
+        for (inst_idx, inst) in bb.instrs.iter().enumerate() {
+            let opcode_inst = match inst {
+                Instruction::Opcode(opcode_inst) => opcode_inst,
+                _ => continue,
+            };
+            let dest: String = match opcode_inst.get_dest() {
+                Some(dest) => dest,
+                None => continue,
+            };
+            graph.add_var(&dest);
+            if let Some(typ) = opcode_inst.get_type() {
+                match graph.types.get(&dest) {
+                    Some(previous) if *previous != typ => {
+                        mixed.insert(dest.clone());
+                    }
+                    _ => {
+                        graph.types.insert(dest.clone(), typ);
+                    }
+                }
+            }
+
+            let copied: Option<&String> = match opcode_inst {
+                OpcodeInstruction::Id { args, .. } => args.first(),
+                _ => None,
+            };
+            for var in live.inst_out[bb_idx][inst_idx].iter() {
+                if Some(var) != copied {
+                    graph.add_edge(&dest, var);
+                }
+            }
+        }
+    }
+    for var in mixed {
+        graph.types.remove(&var);
+    }
+
+    graph
+}
+
+// a set of non-interfering vars sharing the name of the first one in it
+struct Color {
+    name: String,
+    typ: Option<Type>,
+    members: Vec<String>,
+}
+
+// the name every var of the function gets after coalescing
+fn color_variables(function: &Function, graph: &InterferenceGraph) -> HashMap<String, String> {
+    // vars related by a copy, they are tried first so the copy goes away
+    let mut copies: HashMap<String, Vec<String>> = HashMap::new();
+    for inst in function.instrs.iter() {
+        if let Instruction::Opcode(OpcodeInstruction::Id { args, dest, .. }) = inst {
+            copies
+                .entry(dest.clone())
+                .or_default()
+                .push(args[0].clone());
+            copies
+                .entry(args[0].clone())
+                .or_default()
+                .push(dest.clone());
+        }
+    }
+
+    let mut colors: Vec<Color> = Vec::new();
+    let mut var_to_color: HashMap<String, usize> = HashMap::new();
+    for var in graph.vars.iter() {
+        let typ: Option<Type> = graph.types.get(var).cloned();
+        let fits = |color: &Color| -> bool {
+            typ.is_some()
+                && color.typ == typ
+                && color
+                    .members
+                    .iter()
+                    .all(|member| !graph.interferes(member, var))
+        };
+
+        let related: Vec<usize> = copies
+            .get(var)
+            .map(|related| {
+                related
+                    .iter()
+                    .filter_map(|other| var_to_color.get(other).cloned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let color_idx: Option<usize> = related
+            .into_iter()
+            .find(|color_idx| fits(&colors[*color_idx]))
+            .or_else(|| colors.iter().position(fits));
+
+        match color_idx {
+            Some(color_idx) => {
+                colors[color_idx].members.push(var.clone());
+                var_to_color.insert(var.clone(), color_idx);
+            }
+            None => {
+                var_to_color.insert(var.clone(), colors.len());
+                colors.push(Color {
+                    name: var.clone(),
+                    typ,
+                    members: vec![var.clone()],
+                });
+            }
+        }
+    }
+
+    var_to_color
+        .into_iter()
+        .map(|(var, color_idx)| (var, colors[color_idx].name.clone()))
+        .collect()
+}
+
+fn coalesce_function(function: &mut Function) -> bool {
+    let bbs = function.get_basic_blocks();
+    let graph: InterferenceGraph = get_interference_graph(function, &bbs);
+    let names: HashMap<String, String> = color_variables(function, &graph);
+
+    let mut changed: bool = false;
+    for inst in function.instrs.iter_mut() {
+        if let Instruction::Opcode(opcode_inst) = inst {
+            if let Some(dest) = opcode_inst.get_dest_mut() {
+                if let Some(name) = names.get(dest) {
+                    changed |= name != dest;
+                    *dest = name.clone();
+                }
+            }
+            if let Some(args) = opcode_inst.get_args() {
+                for arg in args.iter_mut() {
+                    if let Some(name) = names.get(arg) {
+                        changed |= name != arg;
+                        *arg = name.clone();
+                    }
+                }
+            }
+        }
+    }
+
+    // copies between coalesced vars do nothing now
+    let len_before: usize = function.instrs.len();
+    function.instrs.retain(|inst| {
+        !matches!(
+            inst,
+            Instruction::Opcode(OpcodeInstruction::Id { args, dest, .. }) if args[0] == *dest
+        )
+    });
+    changed |= function.instrs.len() != len_before;
+
+    changed
+}
+
+// reports how many distinct variables every function uses before and after on stderr,
+// stdout is where the program goes
+pub fn coalesce_pass(program: &mut Program) -> bool {
+    let mut changed: bool = false;
+
+    for function in program.functions.iter_mut() {
+        let vars_before: usize = get_variables(function).len();
+        changed |= coalesce_function(function);
+        let vars_after: usize = get_variables(function).len();
+        eprintln!(
+            "@{}: {} -> {} variables",
+            function.name, vars_before, vars_after
+        );
+    }
+
+    changed
+}
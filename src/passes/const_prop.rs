@@ -1,226 +1,534 @@
 use crate::ast;
+use crate::const_eval::fold;
+use crate::dataflow;
+use crate::dom::get_successors;
 use ast::*;
-use std::collections::{HashMap, HashSet, VecDeque};
+use dataflow::*;
+use std::collections::{HashMap, HashSet};
 
-#[derive(Clone)]
-struct ConstantState {
-    constant_values: HashMap<String, serde_json::Value>, // variable identifier -> constant value
+// what's known about a variable. variables missing from a state are undefined: no definition
+// of them reaches the point yet, which says nothing about what they will be.
+#[derive(Clone, PartialEq, Debug)]
+pub enum ConstantValue {
+    Constant(serde_json::Value),
+    Overdefined, // not the same constant along every path
 }
 
-// perform constant prop on a BB
-// var_state: contexual information to the BB
-// returns whether constant prop changes anything, and a new constant prop state
-fn local_constant_prop(bb: &mut BasicBlock, mut ctx: ConstantState) -> (bool, ConstantState) {
-    let mut changed: bool = false;
+#[derive(Clone, PartialEq)]
+pub struct ConstantState {
+    pub constant_values: HashMap<String, ConstantValue>, // variable identifier -> lattice value
+}
+
+impl ConstantState {
+    pub fn get_constant(&self, var: &str) -> Option<&serde_json::Value> {
+        match self.constant_values.get(var) {
+            Some(ConstantValue::Constant(value)) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+fn join_constant_values(a: &ConstantValue, b: &ConstantValue) -> ConstantValue {
+    match (a, b) {
+        (ConstantValue::Constant(x), ConstantValue::Constant(y)) if x == y => a.clone(),
+        _ => ConstantValue::Overdefined,
+    }
+}
+
+// evaluate the instruction if all of its args are known constants
+fn evaluate_constant(
+    opcode_inst: &OpcodeInstruction,
+    ctx: &ConstantState,
+) -> Option<serde_json::Value> {
+    let mut arg_values: Vec<serde_json::Value> = Vec::new();
+    for arg in opcode_inst.get_var_use_list().iter() {
+        arg_values.push(ctx.get_constant(arg)?.clone());
+    }
+    fold(opcode_inst, &arg_values)
+}
+
+// the lattice value an instruction's dest takes, `None` while it's still undefined
+fn evaluate(opcode_inst: &OpcodeInstruction, ctx: &ConstantState) -> Option<ConstantValue> {
+    if let OpcodeInstruction::Const { value, .. } = opcode_inst {
+        return Some(ConstantValue::Constant(value.clone()));
+    }
+    if !Instruction::Opcode(opcode_inst.clone()).has_no_side_effects()
+        || matches!(
+            opcode_inst,
+            OpcodeInstruction::Load { .. } | OpcodeInstruction::Phi { .. }
+        )
+    {
+        return Some(ConstantValue::Overdefined);
+    }
+
+    let mut undefined: bool = false;
+    for arg in opcode_inst.get_var_use_list().iter() {
+        match ctx.constant_values.get(arg) {
+            Some(ConstantValue::Overdefined) => return Some(ConstantValue::Overdefined),
+            Some(ConstantValue::Constant(_)) => {}
+            None => undefined = true,
+        }
+    }
+    // an arg nothing reaches yet might still turn out constant
+    if undefined {
+        return None;
+    }
+    match evaluate_constant(opcode_inst, ctx) {
+        Some(value) => Some(ConstantValue::Constant(value)),
+        None => Some(ConstantValue::Overdefined),
+    }
+}
+
+// the last instruction of `instrs` defining `var`, if what it read still holds at the end of
+// `instrs`
+pub fn get_stable_def<'a>(instrs: &'a [Instruction], var: &str) -> Option<&'a OpcodeInstruction> {
+    let def_idx: usize = instrs
+        .iter()
+        .rposition(|inst| inst.get_result().as_deref() == Some(var))?;
+    let opcode_inst: &OpcodeInstruction = match &instrs[def_idx] {
+        Instruction::Opcode(opcode_inst) => opcode_inst,
+        _ => return None,
+    };
+    // `x = not x` leaves nothing to learn about the old `x`
+    let args: Vec<String> = opcode_inst.get_var_use_list();
+    if args.iter().any(|arg| arg == var) {
+        return None;
+    }
+    let overwritten: bool = instrs[def_idx + 1..]
+        .iter()
+        .any(|inst| match inst.get_result() {
+            Some(dest) => args.contains(&dest),
+            None => false,
+        });
+    if overwritten {
+        None
+    } else {
+        Some(opcode_inst)
+    }
+}
+
+// `var` is known to hold the bool `value` at the end of `instrs`, learn what that says about the
+// vars it was computed from
+fn learn_condition(ctx: &mut ConstantState, instrs: &[Instruction], var: &str, value: bool) {
+    ctx.constant_values.insert(
+        var.to_string(),
+        ConstantValue::Constant(serde_json::Value::Bool(value)),
+    );
+    let def: &OpcodeInstruction = match get_stable_def(instrs, var) {
+        Some(def) => def,
+        None => return,
+    };
+    let args: Vec<String> = def.get_var_use_list();
+    match def {
+        OpcodeInstruction::Id { .. } => learn_condition(ctx, instrs, &args[0], value),
+        OpcodeInstruction::Not { .. } => learn_condition(ctx, instrs, &args[0], !value),
+        // both sides of a true `and` are true, both sides of a false `or` are false
+        OpcodeInstruction::And { .. } | OpcodeInstruction::Or { .. }
+            if value == matches!(def, OpcodeInstruction::And { .. }) =>
+        {
+            learn_condition(ctx, instrs, &args[0], value);
+            learn_condition(ctx, instrs, &args[1], value);
+        }
+        // a var equal to a constant is that constant. zero can't be passed on through `feq`,
+        // `-0.0` equals `0.0` but doesn't print like it.
+        OpcodeInstruction::Eq { .. } | OpcodeInstruction::FEq { .. } if value => {
+            for (known, other) in [(&args[0], &args[1]), (&args[1], &args[0])] {
+                let constant: serde_json::Value = match ctx.get_constant(known) {
+                    Some(constant) => constant.clone(),
+                    None => continue,
+                };
+                let is_float_zero: bool =
+                    matches!(def, OpcodeInstruction::FEq { .. }) && constant.as_f64() == Some(0.0);
+                if !is_float_zero {
+                    ctx.constant_values
+                        .insert(other.clone(), ConstantValue::Constant(constant));
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+struct ConstantPropagation<'a> {
+    entry_state: ConstantState,
+    // callee -> value it returns, `None` while undefined. calls to functions missing here
+    // return something unknown.
+    return_values: &'a HashMap<String, Option<ConstantValue>>,
+}
+
+impl Analysis for ConstantPropagation<'_> {
+    type Fact = ConstantState;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn bottom(&self) -> ConstantState {
+        ConstantState {
+            constant_values: HashMap::new(),
+        }
+    }
+
+    fn boundary(&self) -> ConstantState {
+        self.entry_state.clone()
+    }
+
+    fn join(&self, a: &ConstantState, b: &ConstantState) -> ConstantState {
+        join_constant_states(vec![a, b])
+    }
+
+    // the arm of a branch knows which way its condition went
+    fn transfer_edge(&self, ctx: &mut ConstantState, bbs: &[BasicBlock], from: usize, to: usize) {
+        let instrs: &[Instruction] = &bbs[from].instrs;
+        if let Some(Instruction::Opcode(OpcodeInstruction::Br { args, labels })) = instrs.last() {
+            // both arms going to the same bb tells nothing
+            if labels[0] == labels[1] {
+                return;
+            }
+            if let Some(to_label) = bbs[to].get_label() {
+                if labels.contains(&to_label) {
+                    learn_condition(ctx, instrs, &args[0], to_label == labels[0]);
+                }
+            }
+        }
+    }
 
     // we mutate the constant states as we go through the insts
-    for inst in bb.instrs.iter_mut() {
-        match inst {
-            Instruction::Opcode(opcode_inst) => {
-                // update constant states
-                // insert new constants
-                match opcode_inst {
-                    // constant values gets recoreded into the value table
-                    OpcodeInstruction::Const { dest, value, .. } => {
-                        // TODO: add const prop for other types
-                        if value.is_number() {
-                            ctx.constant_values.insert(dest.clone(), value.clone());
-                            // println!("{} is constant: {}", dest, value);
+    fn transfer_inst(&self, ctx: &mut ConstantState, inst: &Instruction, _: InstLocation) {
+        if let Instruction::Opcode(opcode_inst) = inst {
+            if let Some(dest) = opcode_inst.get_dest() {
+                let value: Option<ConstantValue> = match opcode_inst {
+                    OpcodeInstruction::Call { funcs, .. } => {
+                        match self.return_values.get(&funcs[0]) {
+                            Some(value) => value.clone(),
+                            None => Some(ConstantValue::Overdefined),
                         }
                     }
-                    // none-const values, when re-assigned, gets removed from value table.
-                    _ => {
-                        // remove existing constants if the inst changes value
-                        // TODO: can we do better here? maybe some instructions do self-assignment, or + 0
-                        // maybe this is better handled with LVN?
-                        if let Some(dest) = opcode_inst.get_dest() {
-                            // println!("removing {} as constant due to re-assignment", dest);
-                            ctx.constant_values.remove(&dest);
-                        }
+                    _ => evaluate(opcode_inst, ctx),
+                };
+                match value {
+                    Some(value) => {
+                        ctx.constant_values.insert(dest, value);
+                    }
+                    None => {
+                        ctx.constant_values.remove(&dest);
                     }
                 }
+            }
+        }
+    }
+}
 
-                // replace inst variable uses with constants
-                if let Some(args) = opcode_inst.get_args() {
-                    let mut all_args_constants: bool = true;
-                    let mut arg_values: Vec<&serde_json::Value> = Vec::new();
-                    for arg in args.iter_mut() {
-                        if let Some(value) = ctx.constant_values.get(arg) {
-                            arg_values.push(value);
-                        } else {
-                            all_args_constants = false;
-                            break;
-                        }
-                    }
-                    // if all args are constants, replace inst with an const inst
-                    if all_args_constants {
-                        let evaluated_const_value: Option<serde_json::Value>;
-                        match opcode_inst {
-                            OpcodeInstruction::Add { .. } => {
-                                let mut const_value: i64 = 0;
-                                for val in arg_values {
-                                    const_value += val.as_i64().unwrap();
-                                }
-                                evaluated_const_value = Some(const_value.into());
-                            }
-                            OpcodeInstruction::FAdd { .. } => {
-                                let mut const_value: f64 = 0.0;
-                                for val in arg_values {
-                                    const_value += val.as_f64().unwrap();
-                                }
-                                evaluated_const_value = Some(const_value.into());
-                            }
-                            OpcodeInstruction::Mul { .. } => {
-                                let mut const_value: i64 = 1;
-                                for val in arg_values {
-                                    const_value *= val.as_i64().unwrap();
-                                }
-                                evaluated_const_value = Some(const_value.into());
-                            }
-                            OpcodeInstruction::FMul { .. } => {
-                                let mut const_value: f64 = 1.0;
-                                for val in arg_values {
-                                    const_value *= val.as_f64().unwrap();
-                                }
-                                evaluated_const_value = Some(const_value.into());
-                            }
-                            _ => evaluated_const_value = Option::None,
-                        }
-                        if let Some(value) = evaluated_const_value {
-                            if let Some(dest) = opcode_inst.get_dest() {
-                                if let Some(typ) = opcode_inst.get_type() {
-                                    changed = true;
-                                    // populate const table with new const
-                                    ctx.constant_values.insert(dest.clone(), value.clone());
-                                    // construct new const value
-                                    let const_inst = OpcodeInstruction::Const { dest, typ, value };
-                                    // write back
-                                    *opcode_inst = const_inst;
-                                }
-                            }
-                        }
-                    }
+// perform constant prop on a BB
+// inst_states: constant state right before each of the bb's insts
+// returns whether constant prop changes anything
+fn local_constant_prop(bb: &mut BasicBlock, inst_states: &[ConstantState]) -> bool {
+    let mut changed: bool = false;
+
+    for (inst, ctx) in bb.instrs.iter_mut().zip(inst_states.iter()) {
+        if let Instruction::Opcode(opcode_inst) = inst {
+            if let OpcodeInstruction::Const { .. } = opcode_inst {
+                continue;
+            }
+            // if all args are constants, replace inst with an const inst
+            if let Some(value) = evaluate_constant(opcode_inst, ctx) {
+                if let (Some(dest), Some(typ)) = (opcode_inst.get_dest(), opcode_inst.get_type()) {
+                    changed = true;
+                    // write back
+                    *opcode_inst = OpcodeInstruction::Const { dest, typ, value };
                 }
             }
-            _ => {}
         }
     }
 
-    (changed, ctx)
+    changed
 }
 
-// join constant states. for a constant value of variable `x` to carry on, the following must
-// satisfy:
-// 1. all of the bb's parents have a constate state of variable `x`
-// 2. all of the bb's parents that have the `x` constant state need to have `x` the same const
-//    value.
+// join constant states. a variable undefined in one state takes its value from the others,
+// one that's a different constant along two paths is overdefined.
 fn join_constant_states(states: Vec<&ConstantState>) -> ConstantState {
-    // println!("Joining constant states of {} states", states.len());
     let mut joined_state: ConstantState = ConstantState {
         constant_values: HashMap::new(),
     };
+    for state in states.iter() {
+        for (var, value) in state.constant_values.iter() {
+            let joined_value: ConstantValue = match joined_state.constant_values.get(var) {
+                Some(joined_value) => join_constant_values(joined_value, value),
+                None => value.clone(),
+            };
+            joined_state
+                .constant_values
+                .insert(var.clone(), joined_value);
+        }
+    }
+    joined_state
+}
+
+// nothing is known about the function's args on entry
+fn get_entry_state(function: &Function) -> ConstantState {
+    let mut entry_state: ConstantState = ConstantState {
+        constant_values: HashMap::new(),
+    };
+    if let Some(args) = &function.args {
+        for arg in args.iter() {
+            entry_state
+                .constant_values
+                .insert(arg.name.clone(), ConstantValue::Overdefined);
+        }
+    }
+    entry_state
+}
+
+// constants known at every bb and instruction boundary of the function's bbs, looking at the
+// function on its own
+pub fn get_constant_states(
+    function: &Function,
+    bbs: &[BasicBlock],
+) -> DataflowResult<ConstantState> {
+    let analysis = ConstantPropagation {
+        entry_state: get_entry_state(function),
+        return_values: &HashMap::new(),
+    };
+    solve(&analysis, bbs)
+}
 
-    // count occurance of constant vals, # occurance must be equal to states.len() i.e.
-    // the constant has to exist in all of its parents
+// what holds across calls: the args every function is entered with, and what it returns
+struct InterproceduralConstants {
+    entry_states: HashMap<String, ConstantState>,
+    return_values: HashMap<String, Option<ConstantValue>>,
+}
 
-    // variables -> <# of occurance in parent states, first occurence's value>
-    let mut const_vals: HashMap<String, (usize, serde_json::Value)> = HashMap::new();
+// join `value` into what's known about a var, undefined values don't add anything
+fn join_into(
+    values: &mut HashMap<String, ConstantValue>,
+    var: &str,
+    value: Option<&ConstantValue>,
+) {
+    if let Some(value) = value {
+        let joined_value: ConstantValue = match values.get(var) {
+            Some(known_value) => join_constant_values(known_value, value),
+            None => value.clone(),
+        };
+        values.insert(var.to_string(), joined_value);
+    }
+}
 
-    for state in states.iter() {
-        for (key, val) in state.constant_values.iter() {
-            if let Some(entry) = const_vals.get_mut(key) {
-                if entry.1 == *val {
-                    entry.0 += 1; // increment counter
+// propagate constants over the call graph. args start out undefined and take the join of
+// what every call site passes, return values the join of every `ret`. functions are
+// analyzed with what's known so far until nothing changes, the same optimistic fixpoint as
+// within a function.
+fn get_interprocedural_constants(program: &Program) -> InterproceduralConstants {
+    let mut facts = InterproceduralConstants {
+        entry_states: HashMap::new(),
+        return_values: HashMap::new(),
+    };
+    for function in program.functions.iter() {
+        let entry_state: ConstantState = match function.name.as_str() {
+            // whoever runs the program passes main its args
+            "main" => get_entry_state(function),
+            _ => ConstantState {
+                constant_values: HashMap::new(),
+            },
+        };
+        facts
+            .entry_states
+            .insert(function.name.clone(), entry_state);
+        facts.return_values.insert(function.name.clone(), None);
+    }
+    let params: HashMap<String, Vec<String>> = program
+        .functions
+        .iter()
+        .map(|function| {
+            let names: Vec<String> = match &function.args {
+                Some(args) => args.iter().map(|arg| arg.name.clone()).collect(),
+                None => Vec::new(),
+            };
+            (function.name.clone(), names)
+        })
+        .collect();
+
+    let mut changed: bool = true;
+    while changed {
+        changed = false;
+        for function in program.functions.iter() {
+            let bbs = function.get_basic_blocks();
+            let analysis = ConstantPropagation {
+                entry_state: facts.entry_states[&function.name].clone(),
+                return_values: &facts.return_values,
+            };
+            let result = solve(&analysis, &bbs);
+
+            let mut entry_states: HashMap<String, ConstantState> = facts.entry_states.clone();
+            let mut return_value: HashMap<String, ConstantValue> = HashMap::new();
+            if let Some(Some(value)) = facts.return_values.get(&function.name) {
+                return_value.insert(function.name.clone(), value.clone());
+            }
+            for (bb_idx, bb) in bbs.iter().enumerate() {
+                for (inst, ctx) in bb.instrs.iter().zip(result.inst_in[bb_idx].iter()) {
+                    match inst {
+                        Instruction::Opcode(OpcodeInstruction::Call {
+                            args: Some(args),
+                            funcs,
+                            ..
+                        }) => {
+                            let (Some(entry_state), Some(callee_params)) =
+                                (entry_states.get_mut(&funcs[0]), params.get(&funcs[0]))
+                            else {
+                                continue;
+                            };
+                            for (param, arg) in callee_params.iter().zip(args.iter()) {
+                                join_into(
+                                    &mut entry_state.constant_values,
+                                    param,
+                                    ctx.constant_values.get(arg),
+                                );
+                            }
+                        }
+                        Instruction::Opcode(OpcodeInstruction::Ret { args })
+                            if !args.is_empty() =>
+                        {
+                            join_into(
+                                &mut return_value,
+                                &function.name,
+                                ctx.constant_values.get(&args[0]),
+                            );
+                        }
+                        _ => {}
+                    }
                 }
-            } else {
-                const_vals.insert(key.clone(), (1, val.clone()));
+            }
+
+            let return_value: Option<ConstantValue> = return_value.remove(&function.name);
+            if entry_states != facts.entry_states
+                || facts.return_values[&function.name] != return_value
+            {
+                facts.entry_states = entry_states;
+                facts
+                    .return_values
+                    .insert(function.name.clone(), return_value);
+                changed = true;
             }
         }
     }
 
-    // populate joined_state
-    for elem in const_vals {
-        // println!("checking const val {}", elem.0);
-        if elem.1.0 != states.len() {
-            continue;
+    // args no call site reaches can be anything, the function is only entered from outside
+    for (name, entry_state) in facts.entry_states.iter_mut() {
+        for param in params[name].iter() {
+            if !entry_state.constant_values.contains_key(param) {
+                entry_state
+                    .constant_values
+                    .insert(param.clone(), ConstantValue::Overdefined);
+            }
         }
-        // the same const value of such var has occured for states.len() times,
-        // and all of values are equal. Can join the state.
-        joined_state.constant_values.insert(elem.0, elem.1.1);
     }
-
-    joined_state
+    facts
 }
 
-// constant propagation that operates on a function scope
-fn fn_constant_prop(function: &mut Function) -> bool {
+// turn branches on a known condition into jumps to the arm that's taken
+fn fold_branches(bbs: &mut [BasicBlock], result: &DataflowResult<ConstantState>) -> bool {
     let mut changed: bool = false;
-    let mut bbs = function.get_basic_blocks();
+    for (bb_idx, bb) in bbs.iter_mut().enumerate() {
+        for (inst, ctx) in bb.instrs.iter_mut().zip(result.inst_in[bb_idx].iter()) {
+            if let Instruction::Opcode(OpcodeInstruction::Br { args, labels }) = inst {
+                if let Some(serde_json::Value::Bool(cond)) = ctx.get_constant(&args[0]) {
+                    let taken = if *cond { &labels[0] } else { &labels[1] };
+                    *inst = Instruction::Opcode(OpcodeInstruction::Jmp {
+                        labels: vec![taken.clone()],
+                    });
+                    changed = true;
+                }
+            }
+        }
+    }
+    changed
+}
 
-    // each index corresponds to one bb
-    let mut bb_consts_info: Vec<ConstantState> = Vec::new();
-    bb_consts_info.resize(
-        bbs.len(),
-        ConstantState {
-            constant_values: HashMap::new(),
-        },
-    );
+// drop the bbs control can't reach from the entry anymore, along with phi args coming in
+// from bbs that no longer jump to the phi's bb
+pub fn remove_unreachable_bbs(function: &mut Function) {
+    let bbs = function.get_basic_blocks();
+    let succs: Vec<Vec<usize>> = get_successors(&bbs);
 
-    // worklist of bb indices
-    let mut work_list: VecDeque<usize> = VecDeque::new();
-    let mut in_work_list: HashSet<usize> = HashSet::new(); // indices already in worklist to
-                                                           // prevent repetition
-
-    for i in 0..bbs.len() {
-        work_list.push_back(i);
-        in_work_list.insert(i);
-    }
-
-    // iterate until convergence.
-    while let Some(bb_idx) = work_list.pop_front() {
-        in_work_list.remove(&bb_idx); // no longer in worklist
-                                      //
-        let bb = bbs.get_mut(bb_idx).unwrap();
-        // join all of bb's parents' constant state to figure out bb's initial state
-        let mut parent_states: Vec<&ConstantState> = Vec::new();
-        for parent_idx in bb.in_bb_indices.iter() {
-            parent_states.push(bb_consts_info.get(*parent_idx).unwrap());
-        }
-        // println!("parent states: {}", parent_states.len());
-        let joined_state = join_constant_states(parent_states);
-        let local_constant_prop_res = local_constant_prop(bb, joined_state);
-        // update constant state
-        // here, we update constant state no matter whether there is change.
-        // TODO: add const state hashing, this serves a better indicator than "changed"
-        // flag of local const prop call, which is what we really care about. this will
-        // allow state changes from one bb's parents to be propagated to its children,
-        // whether if the bb's instr changes.
-        let const_state = bb_consts_info.get_mut(bb_idx).unwrap();
-        *const_state = local_constant_prop_res.1;
-        if local_constant_prop_res.0 == true {
-            // changed
-            changed = true;
-            // push all successors of this bb back to the worklist
-            for successor in bb.out_bb_indices.iter() {
-                if !in_work_list.contains(successor) {
-                    in_work_list.insert(*successor);
-                    work_list.push_back(*successor);
-                }
+    let mut reachable: HashSet<usize> = HashSet::new();
+    let mut stack: Vec<usize> = vec![0];
+    while let Some(bb_idx) = stack.pop() {
+        if bbs.is_empty() || !reachable.insert(bb_idx) {
+            continue;
+        }
+        stack.extend(succs[bb_idx].iter().cloned());
+    }
+
+    // labels of the reachable predecessors of every bb
+    let mut pred_labels: Vec<HashSet<String>> = vec![HashSet::new(); bbs.len()];
+    for bb_idx in reachable.iter() {
+        if let Some(label) = bbs[*bb_idx].get_label() {
+            for succ_idx in succs[*bb_idx].iter() {
+                pred_labels[*succ_idx].insert(label.clone());
             }
         }
     }
 
-    if changed {
-        function.update(bbs);
+    let mut reachable_bbs: Vec<BasicBlock> = Vec::new();
+    for (bb_idx, mut bb) in bbs.into_iter().enumerate() {
+        if !reachable.contains(&bb_idx) {
+            continue;
+        }
+        for inst in bb.instrs.iter_mut() {
+            if let Instruction::Opcode(OpcodeInstruction::Phi { args, labels, .. }) = inst {
+                let mut kept_args: Vec<String> = Vec::new();
+                let mut kept_labels: Vec<String> = Vec::new();
+                for (arg, label) in args.iter().zip(labels.iter()) {
+                    if pred_labels[bb_idx].contains(label) {
+                        kept_args.push(arg.clone());
+                        kept_labels.push(label.clone());
+                    }
+                }
+                *args = kept_args;
+                *labels = kept_labels;
+            }
+        }
+        reachable_bbs.push(bb);
     }
+    function.update(reachable_bbs);
+}
 
-    changed
+// constant propagation that operates on a function scope, with what's known across calls
+fn fn_constant_prop(function: &mut Function, facts: &InterproceduralConstants) -> bool {
+    let mut changed: bool = false;
+
+    // every folded branch cuts an edge, and whatever flowed in along it no longer has to agree
+    // at the join, so propagate again on what's left
+    loop {
+        let mut bbs = function.get_basic_blocks();
+        let analysis = ConstantPropagation {
+            entry_state: facts.entry_states[&function.name].clone(),
+            return_values: &facts.return_values,
+        };
+        let result = solve(&analysis, &bbs);
+        if fold_branches(&mut bbs, &result) {
+            function.update(bbs);
+            remove_unreachable_bbs(function);
+            changed = true;
+            continue;
+        }
+
+        // fold with the state right before each inst
+        let mut folded: bool = false;
+        for (bb_idx, bb) in bbs.iter_mut().enumerate() {
+            folded |= local_constant_prop(bb, &result.inst_in[bb_idx]);
+        }
+        if folded {
+            function.update(bbs);
+        }
+        return changed || folded;
+    }
 }
 
 pub fn global_const_propagation_pass(program: &mut Program) -> bool {
     let mut changed: bool = false;
+    let facts: InterproceduralConstants = get_interprocedural_constants(program);
     for function in program.functions.iter_mut() {
-        changed |= fn_constant_prop(function);
+        changed |= fn_constant_prop(function, &facts);
     }
 
     changed
@@ -0,0 +1,195 @@
+// global copy propagation
+//
+// a use of `x` can read `y` directly when the only definition of `x` reaching it is
+// `x = id y`, and `y` hasn't been redefined since. that is a forward must-analysis over
+// "available copies": a copy is available at a point if it executes on every path leading
+// there, and neither side of it is reassigned afterwards.
+use crate::ast;
+use crate::dataflow;
+use crate::passes::def_use::*;
+use crate::passes::reaching_defs::Definition;
+use ast::*;
+use dataflow::*;
+use std::collections::HashSet;
+
+// `dest = id src`, as (dest, src)
+type Copy = (String, String);
+
+// available copies after `inst` executes
+fn transfer(inst: &Instruction, available: &mut HashSet<Copy>) {
+    if let Instruction::Opcode(opcode_inst) = inst {
+        if let Some(dest) = opcode_inst.get_dest() {
+            // either side of a copy getting reassigned kills it
+            available.retain(|(copy_dest, copy_src)| *copy_dest != dest && *copy_src != dest);
+            if let OpcodeInstruction::Id { args, .. } = opcode_inst {
+                if args[0] != dest {
+                    available.insert((dest, args[0].clone()));
+                }
+            }
+        }
+    }
+}
+
+// copies available at every point, `None` where control never got to
+struct AvailableCopies;
+
+impl Analysis for AvailableCopies {
+    type Fact = Option<HashSet<Copy>>;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn bottom(&self) -> Option<HashSet<Copy>> {
+        None
+    }
+
+    // nothing is available on entry
+    fn boundary(&self) -> Option<HashSet<Copy>> {
+        Some(HashSet::new())
+    }
+
+    fn join(&self, a: &Option<HashSet<Copy>>, b: &Option<HashSet<Copy>>) -> Option<HashSet<Copy>> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(a.intersection(b).cloned().collect()),
+            (Some(available), None) | (None, Some(available)) => Some(available.clone()),
+            (None, None) => None,
+        }
+    }
+
+    fn transfer_inst(
+        &self,
+        available: &mut Option<HashSet<Copy>>,
+        inst: &Instruction,
+        _: InstLocation,
+    ) {
+        if let Some(available) = available {
+            transfer(inst, available);
+        }
+    }
+}
+
+// rewrite uses of copies to their sources, returns whether anything changed
+fn propagate_copies(function: &mut Function) -> bool {
+    let mut changed: bool = false;
+    let mut chains = DefUseChains::new(function);
+    let available_copies = solve(&AvailableCopies, chains.get_bbs());
+
+    let mut copies: Vec<(Definition, Copy)> = Vec::new();
+    for (bb_idx, bb) in chains.get_bbs().iter().enumerate() {
+        for (inst_idx, inst) in bb.instrs.iter().enumerate() {
+            if let Instruction::Opcode(OpcodeInstruction::Id { args, dest, .. }) = inst {
+                if args[0] != *dest {
+                    copies.push((
+                        Definition::Inst((bb_idx, inst_idx)),
+                        (dest.clone(), args[0].clone()),
+                    ));
+                }
+            }
+        }
+    }
+
+    // facts are about the values of the original program, rewriting args doesn't change any
+    // of them, so they keep holding while uses get rewritten one after the other
+    for (def, copy) in copies {
+        let uses: Vec<Use> = chains.get_uses(&def);
+        let replaceable: Vec<Use> = uses
+            .iter()
+            .filter(|use_| {
+                let (bb_idx, inst_idx) = use_.location;
+                // phi args are read at the end of the predecessor, not here
+                !chains.get_inst(use_.location).is_phi()
+                    && available_copies.inst_in[bb_idx][inst_idx]
+                        .as_ref()
+                        .is_some_and(|available| available.contains(&copy))
+            })
+            .cloned()
+            .collect();
+
+        if replaceable.is_empty() {
+            continue;
+        }
+        changed = true;
+        if replaceable.len() == uses.len() {
+            chains.replace_all_uses(&def, &copy.1);
+        } else {
+            for use_ in replaceable.iter() {
+                chains.replace_use(use_, &copy.1);
+            }
+        }
+    }
+
+    if changed {
+        chains.write_back(function);
+    }
+    changed
+}
+
+// drop `id`s nobody reads anymore, as well as self copies
+fn remove_dead_copies(function: &mut Function) -> bool {
+    let mut changed: bool = false;
+    let mut chains = DefUseChains::new(function);
+
+    let mut work_list: Vec<InstLocation> = Vec::new();
+    for (bb_idx, bb) in chains.get_bbs().iter().enumerate() {
+        for (inst_idx, inst) in bb.instrs.iter().enumerate() {
+            if let Instruction::Opcode(OpcodeInstruction::Id { .. }) = inst {
+                work_list.push((bb_idx, inst_idx));
+            }
+        }
+    }
+
+    while let Some(location) = work_list.pop() {
+        let def = match chains.get_def_at(location) {
+            Some(def) => def,
+            None => continue, // already removed
+        };
+        if chains.has_uses(&def) {
+            continue;
+        }
+        // the copies feeding this one might not be read by anything else either
+        let feeding_defs: Vec<Definition> = chains
+            .get_uses_at(location)
+            .iter()
+            .flat_map(|use_| chains.get_defs(use_))
+            .collect();
+        chains.remove_inst(location);
+        changed = true;
+        for feeding_def in feeding_defs {
+            if let Definition::Inst(feeding_location) = feeding_def {
+                if let Instruction::Opcode(OpcodeInstruction::Id { .. }) =
+                    chains.get_inst(feeding_location)
+                {
+                    work_list.push(feeding_location);
+                }
+            }
+        }
+    }
+
+    if changed {
+        chains.write_back(function);
+    }
+
+    let before = function.instrs.len();
+    function.instrs.retain(|inst| match inst {
+        Instruction::Opcode(OpcodeInstruction::Id { args, dest, .. }) => args[0] != *dest,
+        _ => true,
+    });
+    changed |= function.instrs.len() != before;
+
+    changed
+}
+
+pub fn copy_propagation_pass(program: &mut Program) -> bool {
+    let mut changed: bool = false;
+
+    for function in program.functions.iter_mut() {
+        // copies of copies only collapse one level at a time across bbs
+        while propagate_copies(function) {
+            changed = true;
+        }
+        changed |= remove_dead_copies(function);
+    }
+
+    changed
+}
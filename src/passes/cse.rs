@@ -0,0 +1,266 @@
+// available expressions and global common subexpression elimination
+//
+// an expression is available at a point if every path there computes it and doesn't change
+// its args afterwards, so recomputing it is redundant. lvn only sees this within a bb.
+use crate::ast;
+use crate::dataflow;
+use ast::*;
+use dataflow::*;
+use std::collections::{HashMap, HashSet};
+
+// the computation an instruction does, regardless of where the result goes
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Expression {
+    pub opcode: &'static str,
+    pub typ: Type,
+    pub args: Vec<String>,
+}
+
+impl Expression {
+    pub fn is_load(&self) -> bool {
+        self.opcode == "load"
+    }
+}
+
+// the expression computed by a pure instruction. consts, copies and phis aren't worth
+// reusing and have nothing to recompute.
+pub fn get_expression(inst: &Instruction) -> Option<Expression> {
+    let opcode_inst = match inst {
+        Instruction::Opcode(opcode_inst) if inst.has_no_side_effects() => opcode_inst,
+        _ => return None,
+    };
+    match opcode_inst {
+        OpcodeInstruction::Const { .. }
+        | OpcodeInstruction::Id { .. }
+        | OpcodeInstruction::Phi { .. } => None,
+        _ => {
+            opcode_inst.get_dest()?;
+            let mut args: Vec<String> = opcode_inst.get_var_use_list();
+            if opcode_inst.is_commutative() {
+                args.sort();
+            }
+            Some(Expression {
+                opcode: opcode_inst.get_opcode_name(),
+                typ: opcode_inst.get_type()?,
+                args,
+            })
+        }
+    }
+}
+
+// whether the instruction might write memory, which changes what loads give back
+fn writes_memory(inst: &Instruction) -> bool {
+    matches!(
+        inst,
+        Instruction::Opcode(
+            OpcodeInstruction::Store { .. }
+                | OpcodeInstruction::Free { .. }
+                | OpcodeInstruction::Call { .. }
+        )
+    )
+}
+
+// whether the expression has to be recomputed after `inst`, because an arg got overwritten
+// or memory changed under a load
+pub fn kills(inst: &Instruction, expression: &Expression) -> bool {
+    let overwrites_arg: bool = match inst {
+        Instruction::Opcode(opcode_inst) => opcode_inst
+            .get_dest()
+            .is_some_and(|dest| expression.args.contains(&dest)),
+        _ => false,
+    };
+    overwrites_arg || (expression.is_load() && writes_memory(inst))
+}
+
+// available expressions right after `inst`
+pub fn transfer_available(inst: &Instruction, available: &mut HashSet<Expression>) {
+    if let Some(expression) = get_expression(inst) {
+        available.insert(expression);
+    }
+    // including possibly the expression just computed
+    available.retain(|expression| !kills(inst, expression));
+}
+
+// expressions only ever computed into one variable that nothing else writes, mapped to that
+// variable. wherever the expression has been computed, the variable still holds its value.
+pub fn get_sole_dests(function: &Function) -> HashMap<Expression, String> {
+    // every variable an expression gets computed into
+    let mut expression_dests: HashMap<Expression, HashSet<String>> = HashMap::new();
+    // vars defined by anything other than one particular expression
+    let mut defined_otherwise: HashSet<String> = HashSet::new();
+    if let Some(fn_args) = &function.args {
+        defined_otherwise.extend(fn_args.iter().map(|arg| arg.name.clone()));
+    }
+    let mut defining_expression: HashMap<String, Expression> = HashMap::new();
+    for inst in function.instrs.iter() {
+        if let Instruction::Opcode(opcode_inst) = inst {
+            if let Some(dest) = opcode_inst.get_dest() {
+                let expression: Option<Expression> = get_expression(inst);
+                if let Some(expression) = &expression {
+                    expression_dests
+                        .entry(expression.clone())
+                        .or_default()
+                        .insert(dest.clone());
+                }
+                match expression {
+                    Some(expression)
+                        if defining_expression
+                            .get(&dest)
+                            .is_none_or(|e| *e == expression) =>
+                    {
+                        defining_expression.insert(dest, expression);
+                    }
+                    _ => {
+                        defined_otherwise.insert(dest);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut sole_dests: HashMap<Expression, String> = HashMap::new();
+    for (expression, dests) in expression_dests {
+        if let Some(dest) = dests.iter().next() {
+            if dests.len() == 1 && !defined_otherwise.contains(dest) {
+                sole_dests.insert(expression, dest.clone());
+            }
+        }
+    }
+    sole_dests
+}
+
+// expressions available at every point, `None` where control never got to
+pub struct AvailableExpressions;
+
+impl Analysis for AvailableExpressions {
+    type Fact = Option<HashSet<Expression>>;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn bottom(&self) -> Option<HashSet<Expression>> {
+        None
+    }
+
+    // nothing is computed on entry
+    fn boundary(&self) -> Option<HashSet<Expression>> {
+        Some(HashSet::new())
+    }
+
+    fn join(
+        &self,
+        a: &Option<HashSet<Expression>>,
+        b: &Option<HashSet<Expression>>,
+    ) -> Option<HashSet<Expression>> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(a.intersection(b).cloned().collect()),
+            (Some(available), None) | (None, Some(available)) => Some(available.clone()),
+            (None, None) => None,
+        }
+    }
+
+    fn transfer_inst(
+        &self,
+        available: &mut Option<HashSet<Expression>>,
+        inst: &Instruction,
+        _: InstLocation,
+    ) {
+        if let Some(available) = available {
+            transfer_available(inst, available);
+        }
+    }
+}
+
+fn cse_fn(function: &mut Function) -> bool {
+    let mut bbs = function.get_basic_blocks();
+    let result = solve(&AvailableExpressions, &bbs);
+
+    // expressions recomputed somewhere they're already available
+    let mut redundant: HashSet<Expression> = HashSet::new();
+    for (bb_idx, bb) in bbs.iter().enumerate() {
+        for (inst_idx, inst) in bb.instrs.iter().enumerate() {
+            if let Some(expression) = get_expression(inst) {
+                if let Some(available) = &result.inst_in[bb_idx][inst_idx] {
+                    if available.contains(&expression) {
+                        redundant.insert(expression);
+                    }
+                }
+            }
+        }
+    }
+    if redundant.is_empty() {
+        return false;
+    }
+
+    // every computation of a redundant expression leaves its value in a holder, the later
+    // ones just copy from there. the original dest does the job if nothing else ever writes
+    // it, otherwise the value goes through a new temporary.
+    let sole_dests: HashMap<Expression, String> = get_sole_dests(function);
+    let mut fresh_names = FreshNameGenerator::new(function);
+    let mut holders: HashMap<Expression, (String, bool)> = HashMap::new(); // holder, is a temp
+    for expression in redundant.iter() {
+        holders.insert(
+            expression.clone(),
+            match sole_dests.get(expression) {
+                Some(dest) => (dest.clone(), false),
+                None => (fresh_names.fresh("cse"), true),
+            },
+        );
+    }
+
+    for (bb_idx, bb) in bbs.iter_mut().enumerate() {
+        let old_instrs: Vec<Instruction> = std::mem::take(&mut bb.instrs);
+        for (inst_idx, inst) in old_instrs.into_iter().enumerate() {
+            let expression: Expression = match get_expression(&inst) {
+                Some(expression) if holders.contains_key(&expression) => expression,
+                _ => {
+                    bb.instrs.push(inst);
+                    continue;
+                }
+            };
+            let (holder, is_temp) = &holders[&expression];
+            let dest: String = inst.get_result().unwrap();
+            let available: bool = result.inst_in[bb_idx][inst_idx]
+                .as_ref()
+                .is_some_and(|available| available.contains(&expression));
+
+            if available {
+                // already computed into the holder on every path here
+                if dest != *holder {
+                    bb.instrs.push(Instruction::Opcode(OpcodeInstruction::Id {
+                        args: vec![holder.clone()],
+                        dest,
+                        typ: expression.typ.clone(),
+                    }));
+                }
+            } else if *is_temp {
+                // compute into the temporary, then copy out
+                let mut computation: Instruction = inst;
+                if let Instruction::Opcode(opcode_inst) = &mut computation {
+                    *opcode_inst.get_dest_mut().unwrap() = holder.clone();
+                }
+                bb.instrs.push(computation);
+                bb.instrs.push(Instruction::Opcode(OpcodeInstruction::Id {
+                    args: vec![holder.clone()],
+                    dest,
+                    typ: expression.typ.clone(),
+                }));
+            } else {
+                bb.instrs.push(inst);
+            }
+        }
+    }
+
+    function.update(bbs);
+    true
+}
+
+// global cse, leaves copies behind for copy propagation to clean up
+pub fn global_cse_pass(program: &mut Program) -> bool {
+    let mut changed: bool = false;
+    for function in program.functions.iter_mut() {
+        changed |= cse_fn(function);
+    }
+    changed
+}
@@ -1,6 +1,8 @@
 use crate::ast;
+use crate::dataflow::InstLocation;
+use crate::passes::def_use::DefUseChains;
 use ast::*;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 
 // perform dce on the bb once, return whether anything changed
 // mutate bb in place
@@ -90,28 +92,37 @@ pub fn local_dce_pass(program: &mut Program) -> bool {
     changed
 }
 
-// function-scope naive dce
+// function-scope naive dce: pure instructions whose result no use can read are dropped. one
+// sweep only, whatever just fed them goes on the next run.
 pub fn naive_dce_pass(program: &mut Program) -> bool {
     let mut changed: bool = false;
-    let mut used_vars: HashSet<String> = HashSet::new();
     for function in program.functions.iter_mut() {
-        let before = function.instrs.len();
-
-        for inst in &function.instrs {
-            let uses = inst.get_use_list();
-            for u in uses {
-                used_vars.insert(u);
+        let mut chains: DefUseChains =
+            DefUseChains::from_ssa(function).unwrap_or_else(|| DefUseChains::new(function));
+
+        let mut dead: Vec<InstLocation> = Vec::new();
+        for (bb_idx, bb) in chains.get_bbs().iter().enumerate() {
+            for (inst_idx, inst) in bb.instrs.iter().enumerate() {
+                if !inst.has_no_side_effects() {
+                    continue; // not pure
+                }
+                // has a result that is being used somewhere else
+                let used: bool = chains
+                    .get_def_at((bb_idx, inst_idx))
+                    .is_some_and(|def| chains.has_uses(&def));
+                if !used {
+                    dead.push((bb_idx, inst_idx));
+                }
             }
         }
 
-        function.instrs.retain(|inst| {
-            !inst.has_no_side_effects() || // not pure
-                inst.get_result().map_or(false, |result| used_vars.contains(&result))
-            // has a result that is being used somewhere else
-        });
-
-        changed = function.instrs.len() != before;
-        used_vars.clear();
+        if !dead.is_empty() {
+            for location in dead {
+                chains.remove_inst(location);
+            }
+            chains.write_back(function);
+            changed = true;
+        }
     }
 
     changed
@@ -0,0 +1,282 @@
+// def-use and use-def chains
+//
+// links every use of a variable to the definitions that can reach it, and back. chains are
+// built from reaching definitions, or straight from the single definitions of a function in
+// ssa form. edits go through the chains so they stay up to date, and get written back to the
+// function at the end.
+//
+// locations stay valid across edits: removed instructions are only dropped on write back.
+// edits never add definitions or change which variable an instruction defines, so the
+// reaching definitions computed up front keep holding.
+use crate::ast;
+use crate::dataflow::InstLocation;
+use crate::passes::reaching_defs::*;
+use ast::*;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+// the `arg_idx`-th arg of the instruction at `location`. for a phi the arg is used at the end
+// of the predecessor it comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Use {
+    pub location: InstLocation,
+    pub arg_idx: usize,
+}
+
+// where the definitions reaching a use come from
+enum DefSource {
+    Reaching(ReachingDefinitions),
+    Ssa(HashMap<String, Definition>), // variable -> its only definition
+}
+
+pub struct DefUseChains {
+    bbs: Vec<BasicBlock>,
+    removed: HashSet<InstLocation>,
+    def_source: DefSource,
+    label_to_bb: HashMap<String, usize>,
+    uses_of_def: HashMap<Definition, BTreeSet<Use>>,
+    defs_of_use: HashMap<Use, BTreeSet<Definition>>,
+}
+
+impl DefUseChains {
+    // chains for any function, using reaching definitions
+    pub fn new(function: &Function) -> DefUseChains {
+        let bbs = function.get_basic_blocks();
+        let reaching_defs = ReachingDefinitions::new(function, &bbs);
+        DefUseChains::build(bbs, DefSource::Reaching(reaching_defs))
+    }
+
+    // chains for a function where every variable has exactly one definition, as in ssa form.
+    // a use can only ever be reached by that definition, so no dataflow is needed. `None` if
+    // some variable is defined more than once.
+    pub fn from_ssa(function: &Function) -> Option<DefUseChains> {
+        let bbs = function.get_basic_blocks();
+        let mut defs: HashMap<String, Definition> = HashMap::new();
+        if let Some(fn_args) = &function.args {
+            for (arg_idx, arg) in fn_args.iter().enumerate() {
+                if defs.insert(arg.name.clone(), Definition::Argument(arg_idx)).is_some() {
+                    return None;
+                }
+            }
+        }
+        for (bb_idx, bb) in bbs.iter().enumerate() {
+            for (inst_idx, inst) in bb.instrs.iter().enumerate() {
+                if let Instruction::Opcode(opcode_inst) = inst {
+                    if let Some(dest) = opcode_inst.get_dest() {
+                        if defs.insert(dest, Definition::Inst((bb_idx, inst_idx))).is_some() {
+                            return None;
+                        }
+                    }
+                }
+            }
+        }
+        Some(DefUseChains::build(bbs, DefSource::Ssa(defs)))
+    }
+
+    fn build(bbs: Vec<BasicBlock>, def_source: DefSource) -> DefUseChains {
+        let mut label_to_bb: HashMap<String, usize> = HashMap::new();
+        for (bb_idx, bb) in bbs.iter().enumerate() {
+            if let Some(label) = bb.get_label() {
+                label_to_bb.insert(label, bb_idx);
+            }
+        }
+
+        let mut chains = DefUseChains {
+            bbs,
+            removed: HashSet::new(),
+            def_source,
+            label_to_bb,
+            uses_of_def: HashMap::new(),
+            defs_of_use: HashMap::new(),
+        };
+        for bb_idx in 0..chains.bbs.len() {
+            for inst_idx in 0..chains.bbs[bb_idx].instrs.len() {
+                chains.link_uses((bb_idx, inst_idx));
+            }
+        }
+        chains
+    }
+
+    // definitions of `var` reaching the use
+    fn lookup_defs(&self, var: &str, use_: &Use) -> BTreeSet<Definition> {
+        let defs: Vec<Definition> = match &self.def_source {
+            DefSource::Ssa(defs) => defs.get(var).cloned().into_iter().collect(),
+            DefSource::Reaching(reaching_defs) => match self.get_inst(use_.location) {
+                Instruction::Opcode(OpcodeInstruction::Phi { labels, .. }) => {
+                    match self.label_to_bb.get(&labels[use_.arg_idx]) {
+                        Some(pred_idx) => reaching_defs.get_reaching_defs_at_bb_end(var, *pred_idx),
+                        None => Vec::new(),
+                    }
+                }
+                _ => reaching_defs.get_reaching_defs(var, use_.location),
+            },
+        };
+        defs.into_iter()
+            .filter(|def| match def {
+                Definition::Inst(location) => !self.removed.contains(location),
+                Definition::Argument(_) => true,
+            })
+            .collect()
+    }
+
+    fn link_use(&mut self, use_: Use) {
+        let var: String = self.get_use_var(&use_);
+        let defs: BTreeSet<Definition> = self.lookup_defs(&var, &use_);
+        for def in defs.iter() {
+            self.uses_of_def.entry(*def).or_default().insert(use_);
+        }
+        self.defs_of_use.insert(use_, defs);
+    }
+
+    fn unlink_use(&mut self, use_: &Use) {
+        if let Some(defs) = self.defs_of_use.remove(use_) {
+            for def in defs.iter() {
+                if let Some(uses) = self.uses_of_def.get_mut(def) {
+                    uses.remove(use_);
+                }
+            }
+        }
+    }
+
+    fn link_uses(&mut self, location: InstLocation) {
+        for use_ in self.get_uses_at(location) {
+            self.link_use(use_);
+        }
+    }
+
+    fn unlink_uses(&mut self, location: InstLocation) {
+        for use_ in self.get_uses_at(location) {
+            self.unlink_use(&use_);
+        }
+    }
+
+    pub fn get_bbs(&self) -> &Vec<BasicBlock> {
+        &self.bbs
+    }
+
+    pub fn get_inst(&self, (bb_idx, inst_idx): InstLocation) -> &Instruction {
+        &self.bbs[bb_idx].instrs[inst_idx]
+    }
+
+    pub fn is_removed(&self, location: InstLocation) -> bool {
+        self.removed.contains(&location)
+    }
+
+    // the definition made by the instruction, if any
+    pub fn get_def_at(&self, location: InstLocation) -> Option<Definition> {
+        match self.get_inst(location) {
+            Instruction::Opcode(opcode_inst)
+                if opcode_inst.get_dest().is_some() && !self.is_removed(location) =>
+            {
+                Some(Definition::Inst(location))
+            }
+            _ => None,
+        }
+    }
+
+    // every use the instruction makes
+    pub fn get_uses_at(&self, location: InstLocation) -> Vec<Use> {
+        if self.is_removed(location) {
+            return Vec::new();
+        }
+        (0..self.get_inst(location).get_var_use_list().len())
+            .map(|arg_idx| Use { location, arg_idx })
+            .collect()
+    }
+
+    // the variable being used
+    pub fn get_use_var(&self, use_: &Use) -> String {
+        self.get_inst(use_.location).get_var_use_list()[use_.arg_idx].clone()
+    }
+
+    // every use a definition can reach, in program order
+    pub fn get_uses(&self, def: &Definition) -> Vec<Use> {
+        match self.uses_of_def.get(def) {
+            Some(uses) => uses.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // every definition that can reach a use, in program order
+    pub fn get_defs(&self, use_: &Use) -> Vec<Definition> {
+        match self.defs_of_use.get(use_) {
+            Some(defs) => defs.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // the definition reaching a use, if there's exactly one
+    pub fn get_unique_def(&self, use_: &Use) -> Option<Definition> {
+        match self.get_defs(use_).as_slice() {
+            [def] => Some(*def),
+            _ => None,
+        }
+    }
+
+    pub fn has_uses(&self, def: &Definition) -> bool {
+        self.uses_of_def
+            .get(def)
+            .is_some_and(|uses| !uses.is_empty())
+    }
+
+    // make the use read `new_var` instead, linking it to the definitions of `new_var` that reach it
+    pub fn replace_use(&mut self, use_: &Use, new_var: &str) {
+        self.unlink_use(use_);
+        let (bb_idx, inst_idx) = use_.location;
+        if let Instruction::Opcode(opcode_inst) = &mut self.bbs[bb_idx].instrs[inst_idx] {
+            if let Some(args) = opcode_inst.get_args() {
+                args[use_.arg_idx] = new_var.to_string();
+            }
+        }
+        self.link_use(*use_);
+    }
+
+    // make every use the definition reaches read `new_var` instead, returns how many changed
+    pub fn replace_all_uses(&mut self, def: &Definition, new_var: &str) -> usize {
+        let uses: Vec<Use> = self.get_uses(def);
+        for use_ in uses.iter() {
+            self.replace_use(use_, new_var);
+        }
+        uses.len()
+    }
+
+    // swap in a new instruction defining the same variable, if any
+    pub fn replace_inst(&mut self, location: InstLocation, new_inst: Instruction) {
+        assert!(!self.is_removed(location));
+        assert_eq!(
+            self.get_inst(location).get_result(),
+            new_inst.get_result(),
+            "replacement has to define the same variable"
+        );
+        self.unlink_uses(location);
+        let (bb_idx, inst_idx) = location;
+        self.bbs[bb_idx].instrs[inst_idx] = new_inst;
+        self.link_uses(location);
+    }
+
+    // delete an instruction. whatever it defines can't be used anymore.
+    pub fn remove_inst(&mut self, location: InstLocation) {
+        if let Some(def) = self.get_def_at(location) {
+            assert!(
+                !self.has_uses(&def),
+                "removing a definition that's still used"
+            );
+            self.uses_of_def.remove(&def);
+        }
+        self.unlink_uses(location);
+        self.removed.insert(location);
+    }
+
+    // flush the edited bbs back to the function
+    pub fn write_back(mut self, function: &mut Function) {
+        for (bb_idx, bb) in self.bbs.iter_mut().enumerate() {
+            let old_instrs: Vec<Instruction> = std::mem::take(&mut bb.instrs);
+            bb.instrs = old_instrs
+                .into_iter()
+                .enumerate()
+                .filter(|(inst_idx, _)| !self.removed.contains(&(bb_idx, *inst_idx)))
+                .map(|(_, inst)| inst)
+                .collect();
+        }
+        function.update(self.bbs);
+    }
+}
@@ -0,0 +1,344 @@
+// dominator-based global value numbering
+//
+// walks the dominator tree with scoped tables: everything numbered in a bb stays visible
+// in the bbs it dominates and is rolled back before moving on to a sibling. an expression
+// already computed in a dominating bb gets replaced by a copy of the variable holding it.
+//
+// on ssa input every variable is defined once and its definition dominates its uses, so
+// value numbers hold everywhere below the definition. plain bril is handled by only
+// trusting such "stable" variables across bbs; a variable that is redefined (or used where
+// its definition doesn't dominate) is only numbered inside the bb that assigned it.
+use crate::ast;
+use crate::dom;
+use ast::*;
+use crate::scoped_table::ScopedTable;
+use dom::*;
+use std::collections::{HashMap, HashSet};
+
+// what a value number stands for
+#[derive(Hash, PartialEq, Eq, Clone)]
+enum ValueKey {
+    Expression {
+        opcode: &'static str,
+        typ: Type,
+        operands: Vec<u32>, // value numbers of the args
+    },
+    Constant {
+        typ: Type,
+        value: String,
+    },
+    // phis are only the same if they merge the same values from the same preds in the same bb
+    Phi {
+        bb_idx: usize,
+        operands: Vec<(String, u32)>, // (label, value number of the arg), sorted by label
+    },
+}
+
+// pure instructions whose result only depends on their args, so two of them with the same
+// opcode and arg values compute the same thing
+fn is_numberable_expression(opcode_inst: &OpcodeInstruction) -> bool {
+    match opcode_inst {
+        OpcodeInstruction::Const { .. }
+        | OpcodeInstruction::Id { .. }
+        | OpcodeInstruction::Phi { .. }
+        | OpcodeInstruction::Load { .. } => false, // load depends on memory
+        _ => {
+            opcode_inst.get_dest().is_some()
+                && Instruction::Opcode(opcode_inst.clone()).has_no_side_effects()
+        }
+    }
+}
+
+// variables defined exactly once (a function argument counts as a definition) whose
+// definition dominates every use. these behave like ssa variables.
+fn get_stable_vars(
+    function: &Function,
+    bbs: &[BasicBlock],
+    dom_context: &DomContext,
+) -> HashSet<String> {
+    // variable -> definitions, (bb idx, inst idx), `None` for function arguments
+    let mut defs: HashMap<String, Vec<Option<(usize, usize)>>> = HashMap::new();
+    if let Some(fn_args) = &function.args {
+        for arg in fn_args.iter() {
+            defs.entry(arg.name.clone()).or_default().push(None);
+        }
+    }
+    for (bb_idx, bb) in bbs.iter().enumerate() {
+        for (inst_idx, inst) in bb.instrs.iter().enumerate() {
+            if let Instruction::Opcode(opcode_inst) = inst {
+                if let Some(dest) = opcode_inst.get_dest() {
+                    defs.entry(dest).or_default().push(Some((bb_idx, inst_idx)));
+                }
+            }
+        }
+    }
+
+    let mut stable_vars: HashSet<String> = defs
+        .iter()
+        .filter(|(_, var_defs)| var_defs.len() == 1)
+        .map(|(var, _)| var.clone())
+        .collect();
+
+    let label_to_bb: HashMap<String, usize> = bbs
+        .iter()
+        .enumerate()
+        .filter_map(|(bb_idx, bb)| bb.get_label().map(|label| (label, bb_idx)))
+        .collect();
+
+    // whether the only definition of `var` is available at instruction `inst_idx` of
+    // `bb_idx`, `None` being the end of the bb
+    let def_dominates = |var: &String, bb_idx: usize, inst_idx: Option<usize>| -> bool {
+        match defs.get(var).map(|var_defs| var_defs[0]) {
+            Some(None) => true,
+            Some(Some((def_bb_idx, def_inst_idx))) => {
+                if def_bb_idx == bb_idx {
+                    inst_idx.is_none_or(|inst_idx| def_inst_idx < inst_idx)
+                } else {
+                    dom_context.bbs[bb_idx].dominators.contains(&def_bb_idx)
+                }
+            }
+            None => false,
+        }
+    };
+
+    for (bb_idx, bb) in bbs.iter().enumerate() {
+        for (inst_idx, inst) in bb.instrs.iter().enumerate() {
+            match inst {
+                // phi args are used at the end of the predecessor they come from
+                Instruction::Opcode(OpcodeInstruction::Phi { args, labels, .. }) => {
+                    for (arg, label) in args.iter().zip(labels.iter()) {
+                        if let Some(pred_idx) = label_to_bb.get(label) {
+                            if !def_dominates(arg, *pred_idx, None) {
+                                stable_vars.remove(arg);
+                            }
+                        }
+                    }
+                }
+                _ => {
+                    for arg in inst.get_var_use_list() {
+                        if !def_dominates(&arg, bb_idx, Some(inst_idx)) {
+                            stable_vars.remove(&arg);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    stable_vars
+}
+
+struct GvnState {
+    next_value_number: u32,
+    stable_vars: HashSet<String>,
+    children: Vec<Vec<usize>>, // dominator tree
+    // variable -> (value number, bb it was numbered in)
+    var_to_value_number: ScopedTable<String, (u32, usize)>,
+    // value -> (value number, variable holding it, bb it was computed in)
+    value_table: ScopedTable<ValueKey, (u32, String, usize)>,
+}
+
+impl GvnState {
+    fn new_value_number(&mut self) -> u32 {
+        self.next_value_number += 1;
+        self.next_value_number
+    }
+
+    // current value number of a variable, if it can be trusted in `bb_idx`
+    fn lookup_var(&self, var: &String, bb_idx: usize) -> Option<u32> {
+        match self.var_to_value_number.get(var) {
+            Some((value_number, numbered_in))
+                if *numbered_in == bb_idx || self.stable_vars.contains(var) =>
+            {
+                Some(*value_number)
+            }
+            _ => None,
+        }
+    }
+
+    // value number of a variable, variables with nothing known get a new one
+    fn get_var_value_number(&mut self, var: &String, bb_idx: usize) -> u32 {
+        match self.lookup_var(var, bb_idx) {
+            Some(value_number) => value_number,
+            None => {
+                let value_number = self.new_value_number();
+                self.var_to_value_number
+                    .insert(var.clone(), (value_number, bb_idx));
+                value_number
+            }
+        }
+    }
+
+    // value number and a variable still holding the value, if the value has been computed
+    fn lookup_value(&self, key: &ValueKey, bb_idx: usize) -> Option<(u32, String)> {
+        let (value_number, holder, _) = self.value_table.get(key)?;
+        // the holder may have been overwritten since
+        if self.lookup_var(holder, bb_idx) == Some(*value_number) {
+            Some((*value_number, holder.clone()))
+        } else {
+            None
+        }
+    }
+
+    fn record_value(&mut self, key: ValueKey, value_number: u32, holder: String, bb_idx: usize) {
+        self.var_to_value_number
+            .insert(holder.clone(), (value_number, bb_idx));
+        self.value_table.insert(key, (value_number, holder, bb_idx));
+    }
+
+    fn push_scope(&mut self) {
+        self.var_to_value_number.push_scope();
+        self.value_table.push_scope();
+    }
+
+    fn pop_scope(&mut self) {
+        self.var_to_value_number.pop_scope();
+        self.value_table.pop_scope();
+    }
+}
+
+// number the bb and everything it dominates, returns whether any instruction got replaced
+fn gvn_bb(state: &mut GvnState, bbs: &mut Vec<BasicBlock>, bb_idx: usize) -> bool {
+    let mut changed: bool = false;
+    state.push_scope();
+
+    for inst_idx in 0..bbs[bb_idx].instrs.len() {
+        let opcode_inst = match &bbs[bb_idx].instrs[inst_idx] {
+            Instruction::Opcode(opcode_inst) => opcode_inst.clone(),
+            _ => continue,
+        };
+        let dest = match opcode_inst.get_dest() {
+            Some(dest) => dest,
+            None => continue,
+        };
+
+        let key: Option<ValueKey> = match &opcode_inst {
+            OpcodeInstruction::Const { typ, value, .. } => Some(ValueKey::Constant {
+                typ: typ.clone(),
+                value: value.to_string(),
+            }),
+            OpcodeInstruction::Id { args, .. } => {
+                // a copy is just another name for the same value
+                let value_number = state.get_var_value_number(&args[0], bb_idx);
+                state
+                    .var_to_value_number
+                    .insert(dest, (value_number, bb_idx));
+                continue;
+            }
+            OpcodeInstruction::Phi { args, labels, .. } => {
+                // back edges bring in args that haven't been numbered yet
+                let operands: Option<Vec<(String, u32)>> = labels
+                    .iter()
+                    .zip(args.iter())
+                    .map(|(label, arg)| {
+                        state
+                            .lookup_var(arg, bb_idx)
+                            .map(|value_number| (label.clone(), value_number))
+                    })
+                    .collect();
+                operands.map(|mut operands| {
+                    operands.sort();
+                    ValueKey::Phi { bb_idx, operands }
+                })
+            }
+            _ if is_numberable_expression(&opcode_inst) => {
+                let mut operands: Vec<u32> = opcode_inst
+                    .get_var_use_list()
+                    .iter()
+                    .map(|arg| state.get_var_value_number(arg, bb_idx))
+                    .collect();
+                if opcode_inst.is_commutative() {
+                    operands.sort();
+                }
+                Some(ValueKey::Expression {
+                    opcode: opcode_inst.get_opcode_name(),
+                    typ: opcode_inst.get_type().unwrap(),
+                    operands,
+                })
+            }
+            _ => None,
+        };
+
+        let key = match key {
+            Some(key) => key,
+            None => {
+                // opaque value, nothing to reuse
+                let value_number = state.new_value_number();
+                state
+                    .var_to_value_number
+                    .insert(dest, (value_number, bb_idx));
+                continue;
+            }
+        };
+
+        match state.lookup_value(&key, bb_idx) {
+            Some((value_number, holder)) => {
+                // redundant, reuse what the holder already computed. constants and phis
+                // are left alone, there's nothing to save on them
+                if let ValueKey::Expression { .. } = key {
+                    if holder != dest {
+                        bbs[bb_idx].instrs[inst_idx] = Instruction::Opcode(OpcodeInstruction::Id {
+                            args: vec![holder],
+                            dest: dest.clone(),
+                            typ: opcode_inst.get_type().unwrap(),
+                        });
+                        changed = true;
+                    }
+                }
+                state
+                    .var_to_value_number
+                    .insert(dest, (value_number, bb_idx));
+            }
+            None => {
+                let value_number = state.new_value_number();
+                state.record_value(key, value_number, dest, bb_idx);
+            }
+        }
+    }
+
+    for child_idx in state.children[bb_idx].clone() {
+        changed |= gvn_bb(state, bbs, child_idx);
+    }
+
+    state.pop_scope();
+    changed
+}
+
+fn gvn_fn(function: &mut Function) -> bool {
+    let mut bbs = function.get_basic_blocks();
+    if bbs.is_empty() {
+        return false;
+    }
+    let dom_context = get_dom_context(&bbs);
+
+    let mut state = GvnState {
+        next_value_number: 0,
+        stable_vars: get_stable_vars(function, &bbs, &dom_context),
+        children: dom_context.get_dom_tree_children(),
+        var_to_value_number: ScopedTable::new(),
+        value_table: ScopedTable::new(),
+    };
+
+    // nothing is known about the arguments, but they're all numbered on entry
+    if let Some(fn_args) = &function.args {
+        for arg in fn_args.iter() {
+            state.get_var_value_number(&arg.name, 0);
+        }
+    }
+
+    let changed = gvn_bb(&mut state, &mut bbs, 0);
+    if changed {
+        function.update(bbs);
+    }
+    changed
+}
+
+pub fn gvn_pass(program: &mut Program) -> bool {
+    let mut changed: bool = false;
+
+    for function in program.functions.iter_mut() {
+        changed |= gvn_fn(function);
+    }
+
+    changed
+}
@@ -0,0 +1,614 @@
+// lazy code motion
+//
+// partial redundancy elimination in the bb-level form of the dragon book. computations of an
+// expression move to the latest points where they're still needed on every path, which pulls
+// loop invariant expressions out in front of the loop and fills in computations on the edges
+// where only some paths into a join computed the expression, so it's not recomputed after.
+// no path ever evaluates an expression more often than before.
+//
+// the analyses run on the cfg with a virtual entry bb in front and an empty bb on every edge
+// into a bb with several preds, so there's always somewhere to put a computation. only the
+// edge bbs that end up with code turn into real code.
+//
+// the value is kept in the variable the expression was computed into when that variable can
+// hold it throughout, otherwise in a new temporary that every computation writes and every
+// original computation copies out of.
+use crate::ast;
+use crate::dataflow;
+use crate::dom::get_successors;
+use crate::passes::cse::*;
+use crate::passes::live::get_liveness;
+use ast::*;
+use dataflow::*;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+// expressions, by index into the function's list of expressions
+type ExpressionSet = BTreeSet<usize>;
+
+// the function's cfg, with a virtual entry at 0 and the function's bbs shifted up by one,
+// followed by the edge bbs
+struct LcmCfg {
+    bbs: Vec<BasicBlock>,
+    num_fn_bbs: usize,
+    edges: Vec<(usize, usize)>, // (pred, succ) each edge bb sits between, in lcm cfg indices
+}
+
+impl LcmCfg {
+    fn new(fn_bbs: &[BasicBlock]) -> LcmCfg {
+        let num_fn_bbs: usize = fn_bbs.len();
+        let mut succs: Vec<Vec<usize>> = vec![vec![1]];
+        for fn_succs in get_successors(fn_bbs) {
+            succs.push(fn_succs.iter().map(|succ_idx| succ_idx + 1).collect());
+        }
+        let mut num_preds: Vec<usize> = vec![0; num_fn_bbs + 1];
+        for bb_succs in succs.iter() {
+            for succ_idx in bb_succs.iter() {
+                num_preds[*succ_idx] += 1;
+            }
+        }
+
+        let mut bbs: Vec<BasicBlock> = vec![BasicBlock {
+            instrs: Vec::new(),
+            in_bb_indices: HashSet::new(),
+            out_bb_indices: HashSet::new(),
+        }];
+        for bb in fn_bbs.iter() {
+            bbs.push(BasicBlock {
+                instrs: bb.instrs.clone(),
+                in_bb_indices: HashSet::new(),
+                out_bb_indices: HashSet::new(),
+            });
+        }
+
+        let mut edges: Vec<(usize, usize)> = Vec::new();
+        for (pred_idx, bb_succs) in succs.iter().enumerate() {
+            for succ_idx in bb_succs.iter() {
+                if num_preds[*succ_idx] > 1 {
+                    let edge_idx: usize = bbs.len();
+                    bbs.push(BasicBlock {
+                        instrs: Vec::new(),
+                        in_bb_indices: HashSet::from([pred_idx]),
+                        out_bb_indices: HashSet::from([*succ_idx]),
+                    });
+                    bbs[pred_idx].out_bb_indices.insert(edge_idx);
+                    bbs[*succ_idx].in_bb_indices.insert(edge_idx);
+                    edges.push((pred_idx, *succ_idx));
+                } else {
+                    bbs[pred_idx].out_bb_indices.insert(*succ_idx);
+                    bbs[*succ_idx].in_bb_indices.insert(pred_idx);
+                }
+            }
+        }
+
+        LcmCfg {
+            bbs,
+            num_fn_bbs,
+            edges,
+        }
+    }
+
+    fn is_fn_bb(&self, bb_idx: usize) -> bool {
+        1 <= bb_idx && bb_idx <= self.num_fn_bbs
+    }
+}
+
+// what each bb does to the expressions on its own
+struct LocalInfo {
+    // computed before any of their args change in the bb
+    used: Vec<ExpressionSet>,
+    // whose args change, or memory under a load, somewhere in the bb
+    killed: Vec<ExpressionSet>,
+    // (bb, expression) -> where the bb first computes it, for the ones in `used`
+    computations: HashMap<(usize, usize), usize>,
+}
+
+impl LocalInfo {
+    fn new(cfg: &LcmCfg, expressions: &[Expression]) -> LocalInfo {
+        let mut used: Vec<ExpressionSet> = vec![ExpressionSet::new(); cfg.bbs.len()];
+        let mut killed: Vec<ExpressionSet> = vec![ExpressionSet::new(); cfg.bbs.len()];
+        let mut computations: HashMap<(usize, usize), usize> = HashMap::new();
+        for (bb_idx, bb) in cfg.bbs.iter().enumerate() {
+            for (inst_idx, inst) in bb.instrs.iter().enumerate() {
+                if let Some(expression) = get_expression(inst) {
+                    let expr_idx: usize =
+                        expressions.iter().position(|e| *e == expression).unwrap();
+                    if !killed[bb_idx].contains(&expr_idx) && used[bb_idx].insert(expr_idx) {
+                        computations.insert((bb_idx, expr_idx), inst_idx);
+                    }
+                }
+                for (expr_idx, expression) in expressions.iter().enumerate() {
+                    if kills(inst, expression) {
+                        killed[bb_idx].insert(expr_idx);
+                    }
+                }
+            }
+        }
+        LocalInfo {
+            used,
+            killed,
+            computations,
+        }
+    }
+}
+
+fn all_expressions(expressions: &[Expression]) -> ExpressionSet {
+    (0..expressions.len()).collect()
+}
+
+// the analyses below only make sense on whole bbs, the per-instruction facts go unused
+
+// expressions computed on every path from the top of a bb before their args change
+struct Anticipated<'a> {
+    local: &'a LocalInfo,
+    all: ExpressionSet,
+}
+
+impl Analysis for Anticipated<'_> {
+    type Fact = ExpressionSet;
+
+    fn direction(&self) -> Direction {
+        Direction::Backward
+    }
+
+    fn bottom(&self) -> ExpressionSet {
+        self.all.clone()
+    }
+
+    fn boundary(&self) -> ExpressionSet {
+        ExpressionSet::new()
+    }
+
+    fn join(&self, a: &ExpressionSet, b: &ExpressionSet) -> ExpressionSet {
+        a.intersection(b).cloned().collect()
+    }
+
+    fn transfer_inst(&self, _: &mut ExpressionSet, _: &Instruction, _: InstLocation) {}
+
+    fn transfer_bb(&self, anticipated: &mut ExpressionSet, _: &BasicBlock, bb_idx: usize) {
+        anticipated.retain(|expr_idx| !self.local.killed[bb_idx].contains(expr_idx));
+        anticipated.extend(self.local.used[bb_idx].iter());
+    }
+}
+
+// expressions that would be available if every computation was hoisted as early as it can go
+struct WillBeAvailable<'a> {
+    local: &'a LocalInfo,
+    anticipated_in: &'a [ExpressionSet],
+    all: ExpressionSet,
+}
+
+impl Analysis for WillBeAvailable<'_> {
+    type Fact = ExpressionSet;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn bottom(&self) -> ExpressionSet {
+        self.all.clone()
+    }
+
+    fn boundary(&self) -> ExpressionSet {
+        ExpressionSet::new()
+    }
+
+    fn join(&self, a: &ExpressionSet, b: &ExpressionSet) -> ExpressionSet {
+        a.intersection(b).cloned().collect()
+    }
+
+    fn transfer_inst(&self, _: &mut ExpressionSet, _: &Instruction, _: InstLocation) {}
+
+    fn transfer_bb(&self, available: &mut ExpressionSet, _: &BasicBlock, bb_idx: usize) {
+        available.extend(self.anticipated_in[bb_idx].iter());
+        available.retain(|expr_idx| !self.local.killed[bb_idx].contains(expr_idx));
+    }
+}
+
+// expressions whose computation can still be put off, on every path to the top of a bb
+struct Postponable<'a> {
+    local: &'a LocalInfo,
+    earliest: &'a [ExpressionSet],
+    all: ExpressionSet,
+}
+
+impl Analysis for Postponable<'_> {
+    type Fact = ExpressionSet;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn bottom(&self) -> ExpressionSet {
+        self.all.clone()
+    }
+
+    fn boundary(&self) -> ExpressionSet {
+        ExpressionSet::new()
+    }
+
+    fn join(&self, a: &ExpressionSet, b: &ExpressionSet) -> ExpressionSet {
+        a.intersection(b).cloned().collect()
+    }
+
+    fn transfer_inst(&self, _: &mut ExpressionSet, _: &Instruction, _: InstLocation) {}
+
+    fn transfer_bb(&self, postponable: &mut ExpressionSet, _: &BasicBlock, bb_idx: usize) {
+        postponable.extend(self.earliest[bb_idx].iter());
+        postponable.retain(|expr_idx| !self.local.used[bb_idx].contains(expr_idx));
+    }
+}
+
+// expressions whose value gets read by some later computation once placed at the latest points
+struct Used<'a> {
+    local: &'a LocalInfo,
+    latest: &'a [ExpressionSet],
+}
+
+impl Analysis for Used<'_> {
+    type Fact = ExpressionSet;
+
+    fn direction(&self) -> Direction {
+        Direction::Backward
+    }
+
+    fn bottom(&self) -> ExpressionSet {
+        ExpressionSet::new()
+    }
+
+    fn boundary(&self) -> ExpressionSet {
+        ExpressionSet::new()
+    }
+
+    fn join(&self, a: &ExpressionSet, b: &ExpressionSet) -> ExpressionSet {
+        a.union(b).cloned().collect()
+    }
+
+    fn transfer_inst(&self, _: &mut ExpressionSet, _: &Instruction, _: InstLocation) {}
+
+    fn transfer_bb(&self, used: &mut ExpressionSet, _: &BasicBlock, bb_idx: usize) {
+        used.extend(self.local.used[bb_idx].iter());
+        used.retain(|expr_idx| !self.latest[bb_idx].contains(expr_idx));
+    }
+}
+
+// where each expression goes in and gets taken out, in lcm cfg bb indices
+struct Placement {
+    insert: Vec<ExpressionSet>,
+    delete: Vec<ExpressionSet>,
+}
+
+impl Placement {
+    // leave every computation of the expression where it is
+    fn keep_in_place(&mut self, expr_idx: usize) {
+        for bb_insert in self.insert.iter_mut() {
+            bb_insert.remove(&expr_idx);
+        }
+        for bb_delete in self.delete.iter_mut() {
+            bb_delete.remove(&expr_idx);
+        }
+    }
+}
+
+fn get_placement(cfg: &LcmCfg, local: &LocalInfo, expressions: &[Expression]) -> Placement {
+    let num_bbs: usize = cfg.bbs.len();
+    let succs: Vec<Vec<usize>> = get_successors(&cfg.bbs);
+    let all: ExpressionSet = all_expressions(expressions);
+
+    let anticipated = solve(
+        &Anticipated {
+            local,
+            all: all.clone(),
+        },
+        &cfg.bbs,
+    );
+    let available = solve(
+        &WillBeAvailable {
+            local,
+            anticipated_in: &anticipated.bb_in,
+            all: all.clone(),
+        },
+        &cfg.bbs,
+    );
+    // first points a computation could go, without being wasted on some path
+    let earliest: Vec<ExpressionSet> = (0..num_bbs)
+        .map(|bb_idx| {
+            anticipated.bb_in[bb_idx]
+                .difference(&available.bb_in[bb_idx])
+                .cloned()
+                .collect()
+        })
+        .collect();
+
+    let postponable = solve(
+        &Postponable {
+            local,
+            earliest: &earliest,
+            all: all.clone(),
+        },
+        &cfg.bbs,
+    );
+    let placeable: Vec<ExpressionSet> = (0..num_bbs)
+        .map(|bb_idx| {
+            earliest[bb_idx]
+                .union(&postponable.bb_in[bb_idx])
+                .cloned()
+                .collect()
+        })
+        .collect();
+    // a computation can't be put off past a bb using it, or an edge leaving for a bb it
+    // can't go into
+    let latest: Vec<ExpressionSet> = (0..num_bbs)
+        .map(|bb_idx| {
+            let mut placeable_in_succs: ExpressionSet = all.clone();
+            for succ_idx in succs[bb_idx].iter() {
+                placeable_in_succs.retain(|expr_idx| placeable[*succ_idx].contains(expr_idx));
+            }
+            placeable[bb_idx]
+                .iter()
+                .filter(|expr_idx| {
+                    local.used[bb_idx].contains(expr_idx) || !placeable_in_succs.contains(expr_idx)
+                })
+                .cloned()
+                .collect()
+        })
+        .collect();
+
+    let used = solve(
+        &Used {
+            local,
+            latest: &latest,
+        },
+        &cfg.bbs,
+    );
+
+    // a bb computing the expression at its latest point just keeps that computation, the
+    // others get a computation at the top if anything after reads it, and lose their own
+    let mut placement = Placement {
+        insert: vec![ExpressionSet::new(); num_bbs],
+        delete: vec![ExpressionSet::new(); num_bbs],
+    };
+    for (bb_idx, bb_latest) in latest.iter().enumerate() {
+        for expr_idx in bb_latest.iter() {
+            if used.bb_out[bb_idx].contains(expr_idx) && !local.used[bb_idx].contains(expr_idx) {
+                placement.insert[bb_idx].insert(*expr_idx);
+            }
+        }
+        for expr_idx in local.used[bb_idx].iter() {
+            if !bb_latest.contains(expr_idx) {
+                placement.delete[bb_idx].insert(*expr_idx);
+            }
+        }
+    }
+    placement
+}
+
+fn lcm_fn(function: &mut Function) -> bool {
+    let bbs = function.get_basic_blocks();
+    if bbs.is_empty() {
+        return false;
+    }
+    let cfg = LcmCfg::new(&bbs);
+
+    let mut expressions: Vec<Expression> = Vec::new();
+    let mut templates: Vec<Instruction> = Vec::new(); // first computation of each expression
+    for inst in function.instrs.iter() {
+        if let Some(expression) = get_expression(inst) {
+            if !expressions.contains(&expression) {
+                expressions.push(expression);
+                templates.push(inst.clone());
+            }
+        }
+    }
+    if expressions.is_empty() {
+        return false;
+    }
+
+    let local = LocalInfo::new(&cfg, &expressions);
+    let mut placement = get_placement(&cfg, &local, &expressions);
+
+    // the variable live at the top of an lcm cfg bb. nothing is in between an edge bb and the
+    // bb it leads to.
+    let live: DataflowResult<HashSet<String>> = get_liveness(&bbs);
+    let lcm_succs: Vec<Vec<usize>> = get_successors(&cfg.bbs);
+    let live_at = |mut bb_idx: usize| -> &HashSet<String> {
+        while !cfg.is_fn_bb(bb_idx) {
+            bb_idx = lcm_succs[bb_idx][0];
+        }
+        &live.bb_in[bb_idx - 1]
+    };
+
+    // an edge bb after a branching pred needs a bb of its own. that's only free if it can sit
+    // right in front of the bb it leads to and fall through, otherwise it costs a jump on the
+    // way out, so the expressions going there stay put.
+    let pred_branches = |pred_idx: usize| -> bool {
+        matches!(
+            bbs[pred_idx - 1].instrs.last(),
+            Some(Instruction::Opcode(OpcodeInstruction::Br { .. }))
+        )
+    };
+    let mut fronts_taken: HashSet<usize> = HashSet::new(); // function bbs getting an edge bb
+    for (edge_idx, (pred_idx, succ_idx)) in cfg.edges.iter().enumerate() {
+        let edge_bb_idx: usize = cfg.num_fn_bbs + 1 + edge_idx;
+        if *pred_idx == 0 || !pred_branches(*pred_idx) || placement.insert[edge_bb_idx].is_empty() {
+            continue;
+        }
+        let fn_succ_idx: usize = succ_idx - 1;
+        let nothing_falls_in: bool = fn_succ_idx > 0
+            && matches!(
+                bbs[fn_succ_idx - 1].instrs.last(),
+                Some(Instruction::Opcode(
+                    OpcodeInstruction::Jmp { .. }
+                        | OpcodeInstruction::Br { .. }
+                        | OpcodeInstruction::Ret { .. }
+                ))
+            );
+        if !(nothing_falls_in && fronts_taken.insert(fn_succ_idx)) {
+            for expr_idx in placement.insert[edge_bb_idx].clone() {
+                placement.keep_in_place(expr_idx);
+            }
+        }
+    }
+
+    let moved: ExpressionSet = (0..cfg.bbs.len())
+        .flat_map(|bb_idx| placement.insert[bb_idx].union(&placement.delete[bb_idx]))
+        .cloned()
+        .collect();
+    if moved.is_empty() {
+        return false;
+    }
+
+    // the variable each moved expression's value is kept in. the original one does the job if
+    // it's the only one the expression is computed into, nothing else writes it, it doesn't
+    // feed the expression itself, and a new computation can't clobber a value still being
+    // read. otherwise the value goes through a new temporary.
+    let sole_dests: HashMap<Expression, String> = get_sole_dests(function);
+    let mut names = FreshNameGenerator::new(function);
+    let mut holders: HashMap<usize, (String, bool)> = HashMap::new(); // holder, is a temp
+    for expr_idx in moved.iter() {
+        let expression: &Expression = &expressions[*expr_idx];
+        let holder: (String, bool) = match sole_dests.get(expression) {
+            Some(dest)
+                if !expression.args.contains(dest)
+                    && (0..cfg.bbs.len()).all(|bb_idx| {
+                        !placement.insert[bb_idx].contains(expr_idx)
+                            || !live_at(bb_idx).contains(dest)
+                    }) =>
+            {
+                (dest.clone(), false)
+            }
+            _ => (names.fresh("lcm"), true),
+        };
+        holders.insert(*expr_idx, holder);
+    }
+
+    let computations_for = |bb_idx: usize| -> Vec<Instruction> {
+        placement.insert[bb_idx]
+            .iter()
+            .map(|expr_idx| {
+                let mut computation: Instruction = templates[*expr_idx].clone();
+                if let Instruction::Opcode(opcode_inst) = &mut computation {
+                    *opcode_inst.get_dest_mut().unwrap() = holders[expr_idx].0.clone();
+                }
+                computation
+            })
+            .collect()
+    };
+
+    // where the new computations go, by function bb:
+    // 1. top of the bb, after its label and phis
+    // 2. edge bb after a pred that only flows into the bb: end of the pred
+    // 3. edge bb after a branching pred: a new bb falling through into the bb
+    // the virtual entry, and the edge bb after it, go in front of the whole function
+    let mut fn_head: Vec<Instruction> = computations_for(0);
+    let mut bb_heads: Vec<Vec<Instruction>> = vec![Vec::new(); bbs.len()];
+    let mut bb_tails: Vec<Vec<Instruction>> = vec![Vec::new(); bbs.len()];
+    let mut bb_fronts: Vec<Vec<Instruction>> = vec![Vec::new(); bbs.len()];
+    let mut retargets: Vec<Vec<(String, String)>> = vec![Vec::new(); bbs.len()]; // (old, new)
+    for bb_idx in 1..=cfg.num_fn_bbs {
+        bb_heads[bb_idx - 1] = computations_for(bb_idx);
+    }
+    for (edge_idx, (pred_idx, succ_idx)) in cfg.edges.iter().enumerate() {
+        let computations: Vec<Instruction> = computations_for(cfg.num_fn_bbs + 1 + edge_idx);
+        if computations.is_empty() {
+            continue;
+        }
+        if *pred_idx == 0 {
+            fn_head.extend(computations);
+            continue;
+        }
+        let fn_pred_idx: usize = pred_idx - 1;
+        if !pred_branches(*pred_idx) {
+            bb_tails[fn_pred_idx].extend(computations);
+        } else {
+            let fn_succ_idx: usize = succ_idx - 1;
+            let bb_label = bbs[fn_succ_idx].get_label().unwrap();
+            let split_label = names.fresh(&format!("{}.lcm", bb_label));
+            bb_fronts[fn_succ_idx].push(Instruction::Label {
+                label: split_label.clone(),
+            });
+            bb_fronts[fn_succ_idx].extend(computations);
+            retargets[fn_pred_idx].push((bb_label, split_label));
+        }
+    }
+
+    let mut instrs: Vec<Instruction> = fn_head;
+    for (fn_bb_idx, bb) in bbs.into_iter().enumerate() {
+        instrs.append(&mut bb_fronts[fn_bb_idx]);
+        let bb_idx: usize = fn_bb_idx + 1;
+        // a deleted computation copies the value out of its holder, one that stays computes
+        // into the temporary first if there is one
+        let mut rewritten: HashMap<usize, (usize, bool)> = HashMap::new(); // expression, deleted
+        for expr_idx in local.used[bb_idx].iter() {
+            if moved.contains(expr_idx) {
+                let deleted: bool = placement.delete[bb_idx].contains(expr_idx);
+                rewritten.insert(local.computations[&(bb_idx, *expr_idx)], (*expr_idx, deleted));
+            }
+        }
+        let mut bb_instrs: Vec<Instruction> = Vec::new();
+        for (inst_idx, mut inst) in bb.instrs.into_iter().enumerate() {
+            let (expr_idx, deleted) = match rewritten.get(&inst_idx) {
+                Some(rewrite) => *rewrite,
+                None => {
+                    bb_instrs.push(inst);
+                    continue;
+                }
+            };
+            let (holder, is_temp) = &holders[&expr_idx];
+            let dest: String = inst.get_result().unwrap();
+            if !deleted {
+                if !is_temp {
+                    bb_instrs.push(inst);
+                    continue;
+                }
+                if let Instruction::Opcode(opcode_inst) = &mut inst {
+                    *opcode_inst.get_dest_mut().unwrap() = holder.clone();
+                }
+                bb_instrs.push(inst);
+            }
+            if dest != *holder {
+                bb_instrs.push(Instruction::Opcode(OpcodeInstruction::Id {
+                    args: vec![holder.clone()],
+                    dest,
+                    typ: expressions[expr_idx].typ.clone(),
+                }));
+            }
+        }
+
+        if let Some(Instruction::Opcode(OpcodeInstruction::Br { labels, .. })) =
+            bb_instrs.last_mut()
+        {
+            for label in labels.iter_mut() {
+                if let Some((_, new_label)) = retargets[fn_bb_idx]
+                    .iter()
+                    .find(|(old_label, _)| old_label == label)
+                {
+                    *label = new_label.clone();
+                }
+            }
+        }
+
+        let head_pos: usize = bb_instrs
+            .iter()
+            .position(|inst| !inst.is_label() && !inst.is_phi())
+            .unwrap_or(bb_instrs.len());
+        bb_instrs.splice(head_pos..head_pos, bb_heads[fn_bb_idx].drain(..));
+        let tail_pos: usize = match bb_instrs.last() {
+            Some(Instruction::Opcode(OpcodeInstruction::Jmp { .. })) => bb_instrs.len() - 1,
+            _ => bb_instrs.len(),
+        };
+        bb_instrs.splice(tail_pos..tail_pos, bb_tails[fn_bb_idx].drain(..));
+
+        instrs.append(&mut bb_instrs);
+    }
+
+    function.instrs = instrs;
+    true
+}
+
+pub fn lazy_code_motion_pass(program: &mut Program) -> bool {
+    let mut changed: bool = false;
+    for function in program.functions.iter_mut() {
+        changed |= lcm_fn(function);
+    }
+    changed
+}
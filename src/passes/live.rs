@@ -1,233 +1,113 @@
 // liveness analysis
 
 use crate::ast;
+use crate::dataflow;
 use ast::*;
-use std::collections::hash_map::DefaultHasher;
-use std::collections::{HashMap, HashSet, VecDeque};
+use dataflow::*;
+use std::collections::HashSet;
 
-// state of liveness passed as program analysis goes,
-// on a bb granularity
-struct LivenessState {
-    pub live_in: Vec<String>,  // vars that are alive when we enter bb
-    pub live_out: Vec<String>, // vars that are alive when we exit bb
-    pub defs: HashSet<String>,
-    pub uses: HashSet<String>,
-}
-
-impl LivenessState {
-    pub fn new() -> LivenessState {
-        LivenessState {
-            live_in: Vec::new(),
-            live_out: Vec::new(),
-            defs: HashSet::new(),
-            uses: HashSet::new(),
-        }
-    }
-}
-
-fn get_bb_use_list(bb: &BasicBlock) -> HashSet<String> {
-    return get_bb_meaningful_use_list(bb);
-    let mut use_list: HashSet<String> = HashSet::new();
+// vars read in the bb before any write to them in the same bb
+fn get_upward_exposed_uses(bb: &BasicBlock) -> HashSet<String> {
+    let mut uses: HashSet<String> = HashSet::new();
+    let mut defs: HashSet<String> = HashSet::new();
     for inst in bb.instrs.iter() {
-        let inst_use_list: Vec<String> = inst.get_use_list();
-        for elem in inst_use_list {
-            use_list.insert(elem);
+        if let Instruction::Opcode(opcode_inst) = inst {
+            for var in opcode_inst.get_var_use_list() {
+                if !defs.contains(&var) {
+                    uses.insert(var);
+                }
+            }
+            if let Some(dest) = opcode_inst.get_dest() {
+                defs.insert(dest);
+            }
         }
     }
-    use_list
+    uses
 }
 
-fn get_bb_meaningful_use_list(bb: &BasicBlock) -> HashSet<String> {
-    let mut use_list: HashSet<String> = HashSet::new();
+// vars written somewhere in the bb
+fn get_kills(bb: &BasicBlock) -> HashSet<String> {
+    let mut kills: HashSet<String> = HashSet::new();
     for inst in bb.instrs.iter() {
-        if !inst.is_meaningful() {
-            continue;
-        }
-        let mut inst_use_list: Vec<String> = inst.get_use_list();
-        for elem in inst_use_list {
-            use_list.insert(elem);
+        if let Instruction::Opcode(opcode_inst) = inst {
+            if let Some(dest) = opcode_inst.get_dest() {
+                kills.insert(dest);
+            }
         }
     }
-    use_list
+    kills
 }
 
-fn get_bb_def_list(bb: &BasicBlock) -> HashSet<String> {
-    let mut def_list: HashSet<String> = HashSet::new();
-    for inst in bb.instrs.iter() {
-        if let Some(result) = inst.get_result() {
-            def_list.insert(result);
-        }
-    }
-    def_list
+// vars whose current value may still be read, at every point of a function
+struct LiveVars {
+    uses: Vec<HashSet<String>>, // bb idx -> upward exposed uses
+    kills: Vec<HashSet<String>>,
 }
 
-// TODO: this is pretty jank, we're copying memory chunks around,
-// not hashing states etc.... i need to get better at rust to do this
-fn bb_update_liveness(
-    bb: &BasicBlock,
-    state: &LivenessState,
-    predecessors: &Vec<&LivenessState>,
-    successors: &Vec<&LivenessState>,
-) -> (LivenessState, bool) {
-    let mut new_state: LivenessState = LivenessState::new();
-    // i'm agreeing with Jonathan Blow here, rust's problems is that it
-    // creates so much friction in problem solving that it makes remotely difficult
-    // problems 10x harder to solve, and perf isn't necessarily better unless you're a
-    // master rust programmer
-    new_state.defs = get_bb_def_list(bb);
-    new_state.uses = get_bb_use_list(bb);
-
-    // bb's live out is union of all its successors' live in
-    for succ in successors.iter() {
-        for var in succ.live_in.iter() {
-            new_state.live_out.push(var.clone());
+impl LiveVars {
+    fn new(bbs: &[BasicBlock]) -> LiveVars {
+        LiveVars {
+            uses: bbs.iter().map(get_upward_exposed_uses).collect(),
+            kills: bbs.iter().map(get_kills).collect(),
         }
     }
-
-    // bb's live in is its used vars, union all its live out - all its defed vars
-    for used in new_state.uses.iter() {
-        new_state.live_in.push(used.clone());
-    }
-
-    let mut live_out_without_local_def: Vec<String> = new_state.live_out.clone();
-    live_out_without_local_def.retain(|elem| !new_state.defs.contains(elem));
-
-    // FIXME: use hashmap, no time because it's overdue for now
-    for elem in live_out_without_local_def.iter() {
-        if !new_state.live_in.contains(elem) {
-            new_state.live_in.push(elem.clone());
-        }
-    }
-
-    let changed: bool = new_state.live_out.len() != state.live_out.len()
-        || new_state.live_in.len() != state.live_in.len();
-    (new_state, changed)
 }
 
-// function scope global dce
-fn global_dce_on_function(function: &mut Function) -> bool {
-    let mut changed: bool = false;
-
-    let mut bbs = function.get_basic_blocks();
-    let mut liveness_states: Vec<LivenessState> = Vec::new();
-    let mut bb_pre_succ_liveness_states: Vec<(Vec<&LivenessState>, Vec<&LivenessState>)> =
-        Vec::new();
+impl Analysis for LiveVars {
+    type Fact = HashSet<String>;
 
-    let mut work_list: VecDeque<usize> = VecDeque::new();
-    let mut in_work_list: HashSet<usize> = HashSet::new(); // indices already in worklist to
-                                                           // prevent repetition
-                                                           // populate default states
-    for _i in 0..bbs.len() {
-        liveness_states.push(LivenessState::new());
+    fn direction(&self) -> Direction {
+        Direction::Backward
     }
 
-    // populate def-use
-    for i in 0..bbs.len() {
-        let bb = &bbs[i];
-        let state: &mut LivenessState = liveness_states.get_mut(i).unwrap();
-        state.defs = get_bb_def_list(bb);
-        state.uses = get_bb_use_list(bb);
+    fn bottom(&self) -> HashSet<String> {
+        HashSet::new()
     }
 
-    // debug print def-use
-    // for i in 0..bbs.len() {
-    //     let state = liveness_states.get(i).unwrap();
-    //     println!("bb {} defs: ", i);
-    //     for d in state.defs.iter() {
-    //         print!(" {}", d);
-    //     }
-    //     println!();
-    //
-    //     println!("bb {} uses: ", i);
-    //     for u in state.uses.iter() {
-    //         print!(" {}", u);
-    //     }
-    //     println!();
-    // }
-
-    // populate lookup table to pre and successors of a bb's liveness states
-    for bb in bbs.iter() {
-        let mut pre_succ_liveness_states: (Vec<&LivenessState>, Vec<&LivenessState>) =
-            (Vec::new(), Vec::new());
-        for i in bb.in_bb_indices.iter() {
-            pre_succ_liveness_states.0.push(&liveness_states[*i]);
-        }
-        for i in bb.out_bb_indices.iter() {
-            pre_succ_liveness_states.1.push(&liveness_states[*i]);
-        }
-        bb_pre_succ_liveness_states.push(pre_succ_liveness_states);
+    fn boundary(&self) -> HashSet<String> {
+        HashSet::new()
     }
 
-    // construct worklist
-    for i in (0..bbs.len()).rev() {
-        work_list.push_back(i);
-        in_work_list.insert(i);
+    // bb's live out is union of all its successors' live in
+    fn join(&self, a: &HashSet<String>, b: &HashSet<String>) -> HashSet<String> {
+        a.union(b).cloned().collect()
     }
 
-    while let Some(bb_idx) = work_list.pop_front() {
-        //println!("updating liveness for bb {}", bb_idx);
-        in_work_list.remove(&bb_idx);
-        let bb = bbs.get_mut(bb_idx).unwrap();
-        let liveness_state: &LivenessState = liveness_states.get(bb_idx).unwrap();
-        let mut parent_liveness_states: Vec<&LivenessState> = Vec::new();
-        let mut children_liveness_states: Vec<&LivenessState> = Vec::new();
-        //println!(" has {} parents", bb.in_bb_indices.len());
-        for parent_idx in bb.in_bb_indices.iter() {
-            // println!("parent {}", parent_idx);
-            parent_liveness_states.push(liveness_states.get(*parent_idx).unwrap());
-        }
-
-        //println!(" has {} children", bb.out_bb_indices.len());
-        for child_idx in bb.out_bb_indices.iter() {
-            //println!("children {}", child_idx);
-            children_liveness_states.push(liveness_states.get(*child_idx).unwrap());
-        }
-        let res = bb_update_liveness(
-            bb,
-            liveness_state,
-            &parent_liveness_states,
-            &children_liveness_states,
-        );
-
-        if res.1 {
-            // update liveness
-            *liveness_states.get_mut(bb_idx).unwrap() = res.0;
-            // push all parents onto worklist
-            for parent_idx in bb.in_bb_indices.iter() {
-                if in_work_list.insert(parent_idx.clone()) {
-                    work_list.push_back(parent_idx.clone());
-                }
+    fn transfer_inst(&self, live: &mut HashSet<String>, inst: &Instruction, _: InstLocation) {
+        if let Instruction::Opcode(opcode_inst) = inst {
+            if let Some(dest) = opcode_inst.get_dest() {
+                live.remove(&dest);
             }
+            live.extend(opcode_inst.get_var_use_list());
         }
     }
 
-    // debug print
-    if false {
-        for i in 0..bbs.len() {
-            println!("bb {}'s liveness:", i);
-            let liveness = liveness_states.get(i).unwrap();
-            println!("Live in: ");
-            for elem in liveness.live_in.iter() {
-                print!(" {}", elem);
-            }
-            println!();
+    // live in = upward exposed uses, union (live out - kills)
+    fn transfer_bb(&self, live: &mut HashSet<String>, _: &BasicBlock, bb_idx: usize) {
+        live.retain(|var| !self.kills[bb_idx].contains(var));
+        live.extend(self.uses[bb_idx].iter().cloned());
+    }
+}
 
-            println!("Live out: ");
-            for elem in liveness.live_out.iter() {
-                print!(" {}", elem);
-            }
+// live vars at the top (`bb_in`) and bottom (`bb_out`) of every bb, and right before
+// (`inst_in`) and after (`inst_out`) every instruction
+pub fn get_liveness(bbs: &[BasicBlock]) -> DataflowResult<HashSet<String>> {
+    solve(&LiveVars::new(bbs), bbs)
+}
 
-            println!();
-        }
-    }
+// function scope global dce
+fn global_dce_on_function(function: &mut Function) -> bool {
+    let mut changed: bool = false;
+
+    let mut bbs = function.get_basic_blocks();
+    let liveness = get_liveness(&bbs);
 
     // block-scope liveness analysis done,
     // now perform instruction-granularity liveness analysis/DCE
     for bb_idx in 0..bbs.len() {
         let bb: &mut BasicBlock = bbs.get_mut(bb_idx).unwrap();
 
-        let live_out: Vec<String> = liveness_states.get(bb_idx).unwrap().live_out.clone();
-        let mut live_out: HashSet<String> = live_out.into_iter().collect();
+        let mut live_out: HashSet<String> = liveness.bb_out[bb_idx].clone();
 
         let mut insts_to_pop: Vec<usize> = Vec::new();
         // reverse traverse the insts
@@ -235,25 +115,27 @@ fn global_dce_on_function(function: &mut Function) -> bool {
             let inst = bb.instrs.get(inst_idx).unwrap();
             let mut inst_is_dead: bool = true;
 
-            if let Some(dest) = inst.get_result() {
+            let dest: Option<String> = match inst {
+                Instruction::Opcode(opcode_inst) => opcode_inst.get_dest(),
+                _ => None,
+            };
+            if let Some(dest) = dest {
                 if live_out.contains(&dest) {
                     inst_is_dead = false;
-                    // this is the latest point where we assign to
-                    // the live_out, we now can safely remove it
-                    live_out.remove(&dest);
-                    // all vars used by the inst are needed
-                    // this gracefully handles self-referential vars as a regular case.
-                    for var_used in inst.get_use_list() {
-                        live_out.insert(var_used);
-                    }
                 }
+                // whether it's kept or not, this write ends the live range of the
+                // value flowing out, earlier writes to `dest` can't reach past it
+                live_out.remove(&dest);
             }
 
             if inst.is_meaningful() {
                 inst_is_dead = false;
-                for u in inst.get_use_list() {
-                    live_out.insert(u);
-                }
+            }
+
+            // all vars used by a kept inst are needed.
+            // this gracefully handles self-referential vars as a regular case.
+            if !inst_is_dead {
+                live_out.extend(inst.get_var_use_list());
             }
 
             if inst_is_dead {
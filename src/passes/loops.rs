@@ -5,7 +5,7 @@ use dom::*;
 
 use std::collections::{HashSet, VecDeque};
 
-struct Loop {
+pub struct Loop {
     pub header_idx: usize,
     pub back_node_idx: usize, // node that back-edges back to the header
     pub nodes: Vec<usize>,    // all nodes execept for header and back node
@@ -58,7 +58,9 @@ fn create_and_insert_pre_header(
     // to the old header don't need to be changed
     bbs.insert(header_idx, pre_header);
 
-    if header_idx < back_node_idx {
+    // the back node moves along with everything from the header on, a self loop's back node
+    // is the old header itself
+    if header_idx <= back_node_idx {
         back_node_idx += 1;
     }
 
@@ -128,6 +130,61 @@ fn create_and_insert_pre_header(
     }
 }
 
+// one loop per back edge, with no nodes yet
+fn get_back_edge_loops(bbs: &[BasicBlock], dom_context: &DomContext) -> Vec<Loop> {
+    let mut loops: Vec<Loop> = Vec::new();
+
+    // find loops using back-edges, by itearting over all edges and check dom tree(technically can
+    // also use dfs to figure this out, without dom tree)
+    for (bb_idx, bb) in bbs.iter().enumerate() {
+        let bb_dom_context: &BBDomContext = &dom_context.bbs[bb_idx];
+        for out_bb_idx in bb.out_bb_indices.iter() {
+            // we have a back edge if the dest of an edge dominates src
+            if bb_dom_context.dominators.contains(out_bb_idx) {
+                loops.push(Loop {
+                    header_idx: *out_bb_idx,
+                    back_node_idx: bb_idx,
+                    nodes: Vec::new(),
+                });
+            }
+        }
+    }
+
+    loops
+}
+
+// walk back from the back node up to the header, collecting everything in between
+fn populate_loop_nodes(loop_: &mut Loop, bbs: &[BasicBlock]) {
+    // a bb looping to itself has nothing in between, walking its preds would leave the loop
+    if loop_.back_node_idx == loop_.header_idx {
+        return;
+    }
+    let mut work_list: Vec<usize> = vec![loop_.back_node_idx];
+    let mut processed: HashSet<usize> = HashSet::from([loop_.back_node_idx]);
+
+    while let Some(node_bb_idx) = work_list.pop() {
+        // add all predecessors of the current node, that are not the header node, to the wl as well as
+        // the nodes list
+        for parent_idx in bbs[node_bb_idx].in_bb_indices.iter() {
+            if *parent_idx == loop_.header_idx || !processed.insert(*parent_idx) {
+                continue;
+            }
+            loop_.nodes.push(*parent_idx);
+            work_list.push(*parent_idx);
+        }
+    }
+}
+
+// natural loops of the function's bbs, one per back edge
+pub fn get_natural_loops(bbs: &[BasicBlock]) -> Vec<Loop> {
+    let dom_context: DomContext = get_dom_context(bbs);
+    let mut loops: Vec<Loop> = get_back_edge_loops(bbs, &dom_context);
+    for loop_ in loops.iter_mut() {
+        populate_loop_nodes(loop_, bbs);
+    }
+    loops
+}
+
 fn licm_loop(loop_: &mut Loop, bbs: &mut Vec<BasicBlock>) -> bool {
     let mut changed: bool = false;
     let mut bbs_to_hoist: Vec<Vec<usize>> = Vec::new();
@@ -182,29 +239,7 @@ fn licm_function(function: &mut Function) -> bool {
     let mut bbs: Vec<BasicBlock> = function.get_basic_blocks();
 
     let dom_context: DomContext = get_dom_context(&bbs);
-
-    let mut loops: Vec<Loop> = Vec::new();
-
-    // find loops using back-edges, by itearting over all edges and check dom tree(technically can
-    // also use dfs to figure this out, without dom tree)
-    for (bb_idx, bb) in bbs.iter().enumerate() {
-        let bb_dom_context: &BBDomContext;
-        unsafe {
-            bb_dom_context = dom_context.bbs.get_unchecked(bb_idx);
-        }
-        for out_bb_idx in bb.out_bb_indices.iter() {
-            // we have a back edge if the dest of an edge dominates src
-            let is_back_edge: bool = bb_dom_context.dominators.contains(out_bb_idx);
-            if is_back_edge {
-                let new_loop: Loop = Loop {
-                    header_idx: out_bb_idx.clone(),
-                    back_node_idx: bb_idx,
-                    nodes: Vec::new(),
-                };
-                loops.push(new_loop);
-            }
-        }
-    }
+    let mut loops: Vec<Loop> = get_back_edge_loops(&bbs, &dom_context);
 
     // add pre-header
     for loop_ in loops.iter_mut() {
@@ -213,27 +248,7 @@ fn licm_function(function: &mut Function) -> bool {
 
     // populate loop nodes
     for loop_ in loops.iter_mut() {
-        let starting_node = loop_.back_node_idx.clone();
-        let mut work_list: Vec<usize> = vec![starting_node];
-        let mut processed: HashSet<usize> = HashSet::new();
-        processed.insert(starting_node);
-
-        while let Some(node_bb_idx) = work_list.pop() {
-            // add all predecessors of the current node, that are not the header node, to the wl as well as
-            // the nodes list
-            let bb;
-            unsafe {
-                bb = bbs.get_unchecked(node_bb_idx);
-            }
-            for parent_idx in bb.in_bb_indices.iter() {
-                if parent_idx.clone() == loop_.header_idx || processed.contains(parent_idx) {
-                    continue;
-                }
-                processed.insert(parent_idx.clone());
-                loop_.nodes.push(parent_idx.clone());
-                work_list.push(parent_idx.clone());
-            }
-        }
+        populate_loop_nodes(loop_, &bbs);
     }
 
     for loop_ in loops.iter_mut() {
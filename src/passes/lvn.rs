@@ -1,152 +1,398 @@
 // local value numbering
+//
+// every value a bb computes gets a number. expressions are keyed by their opcode, type and the
+// value numbers of their args, so recomputing a value is caught no matter which variables it
+// comes through. the recomputation then copies from a variable still holding the value.
+//
+// value numbers also remember known constants, so expressions on them get folded, and
+// algebraic identities turn expressions into constants or copies of their args.
+//
+// numbering runs over extended bbs, trees of bbs where every bb but the root has the one
+// predecessor. a bb starts from what its predecessor knew at its end, and whatever it learns
+// is rolled back before its siblings are numbered.
 use crate::ast;
+use crate::const_eval::fold;
+use crate::dom::get_successors;
+use crate::scoped_table::ScopedTable;
 use ast::*;
-use std::collections::{HashMap, HashSet};
-
-fn hash_expr(args: &Vec<String>, typ: &Type, opcode: &str) -> String {
-    let mut hash: String = String::new();
-    // opcode
-    hash.push_str(&opcode);
-    // type
-    hash.push_str(&typ.to_string());
-
-    // arguments
-    args.iter().for_each(|arg| hash.push_str(arg));
-    hash
+use serde_json::Value;
+use std::collections::HashMap;
+
+type ValueNumber = usize;
+
+// what a value number stands for
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum ValueKey {
+    Const(Type, String), // the literal, serialized
+    Expr(&'static str, Type, Vec<ValueNumber>),
+}
+
+// comparisons the other way around, `gt a b` is `lt b a`
+fn get_flipped_opcode(opcode: &str) -> Option<&'static str> {
+    match opcode {
+        "gt" => Some("lt"),
+        "ge" => Some("le"),
+        "fgt" => Some("flt"),
+        "fge" => Some("fle"),
+        _ => None,
+    }
+}
+
+// what an expression boils down to without computing it
+enum Simplified {
+    Const(Value),
+    Copy(ValueNumber),
 }
 
-fn hash_commutitative_expr(args: &Vec<String>, typ: &Type, opcode: &str) -> String {
-    let mut args_sorted = args.clone();
-    args_sorted.sort();
-    let mut hash = String::new();
-    args_sorted.iter().for_each(|arg| hash.push_str(arg));
-    hash_expr(&args_sorted, typ, opcode)
+// value numbers are never reused across the extended bb, so what's known about a number
+// itself doesn't need rolling back, only which numbers are reachable and who holds them
+struct ValueTable {
+    next_num: ValueNumber,
+    key_to_num: ScopedTable<ValueKey, ValueNumber>,
+    num_to_key: HashMap<ValueNumber, ValueKey>,
+    constants: HashMap<ValueNumber, Value>, // value numbers known to be constants
+    holders: ScopedTable<ValueNumber, String>, // variable the value was first computed into
+    var_to_num: ScopedTable<String, ValueNumber>,
 }
 
-fn get_rhs_hash(opcode_inst: &OpcodeInstruction) -> Option<String> {
-    match opcode_inst {
-        // commutative operations
-        OpcodeInstruction::Add { args, dest, typ } => {
-            Some(hash_commutitative_expr(args, typ, "add"))
+impl ValueTable {
+    fn new() -> ValueTable {
+        ValueTable {
+            next_num: 0,
+            key_to_num: ScopedTable::new(),
+            num_to_key: HashMap::new(),
+            constants: HashMap::new(),
+            holders: ScopedTable::new(),
+            var_to_num: ScopedTable::new(),
+        }
+    }
+
+    fn push_scope(&mut self) {
+        self.key_to_num.push_scope();
+        self.holders.push_scope();
+        self.var_to_num.push_scope();
+    }
+
+    fn pop_scope(&mut self) {
+        self.key_to_num.pop_scope();
+        self.holders.pop_scope();
+        self.var_to_num.pop_scope();
+    }
+
+    fn new_num(&mut self, holder: &str) -> ValueNumber {
+        let num: ValueNumber = self.next_num;
+        self.next_num += 1;
+        self.holders.insert(num, holder.to_string());
+        num
+    }
+
+    // a variable the bb hasn't assigned yet holds some value from before the bb
+    fn get_var_num(&mut self, var: &str) -> ValueNumber {
+        match self.var_to_num.get(&var.to_string()) {
+            Some(num) => *num,
+            None => {
+                let num: ValueNumber = self.new_num(var);
+                self.var_to_num.insert(var.to_string(), num);
+                num
+            }
+        }
+    }
+
+    // the value an instruction computes, `None` if it isn't worth numbering or it isn't pure
+    fn get_key(opcode_inst: &OpcodeInstruction, arg_nums: &[ValueNumber]) -> Option<ValueKey> {
+        let typ: Type = opcode_inst.get_type()?;
+        match opcode_inst {
+            OpcodeInstruction::Const { value, .. } => Some(ValueKey::Const(typ, value.to_string())),
+            // memory can change between loads, and every alloc is a new pointer
+            OpcodeInstruction::Load { .. }
+            | OpcodeInstruction::Alloc { .. }
+            | OpcodeInstruction::Call { .. }
+            | OpcodeInstruction::Phi { .. }
+            | OpcodeInstruction::Id { .. } => None,
+            _ => {
+                if !Instruction::Opcode(opcode_inst.clone()).has_no_side_effects() {
+                    return None;
+                }
+                opcode_inst.get_dest()?;
+                let mut opcode: &'static str = opcode_inst.get_opcode_name();
+                let mut args: Vec<ValueNumber> = arg_nums.to_vec();
+                if opcode_inst.is_commutative() {
+                    args.sort();
+                } else if let Some(flipped_opcode) = get_flipped_opcode(opcode) {
+                    opcode = flipped_opcode;
+                    args.reverse();
+                }
+                Some(ValueKey::Expr(opcode, typ, args))
+            }
         }
-        OpcodeInstruction::FAdd { args, dest, typ } => {
-            Some(hash_commutitative_expr(args, typ, "Fadd"))
+    }
+
+    // fold the expression if its args are constants, or apply an identity
+    fn simplify(
+        &self,
+        opcode_inst: &OpcodeInstruction,
+        arg_nums: &[ValueNumber],
+    ) -> Option<Simplified> {
+        let arg_values: Option<Vec<Value>> = arg_nums
+            .iter()
+            .map(|num| self.constants.get(num).cloned())
+            .collect();
+        if let Some(value) = arg_values.and_then(|arg_values| fold(opcode_inst, &arg_values)) {
+            return Some(Simplified::Const(value));
         }
-        OpcodeInstruction::Mul { args, dest, typ } => {
-            Some(hash_commutitative_expr(args, typ, "Mul"))
+
+        let is =
+            |arg_idx: usize, value: Value| self.constants.get(&arg_nums[arg_idx]) == Some(&value);
+        let same_args: bool = arg_nums.len() == 2 && arg_nums[0] == arg_nums[1];
+        let copy = |arg_idx: usize| Some(Simplified::Copy(arg_nums[arg_idx]));
+        match opcode_inst {
+            OpcodeInstruction::Add { .. } if is(1, 0.into()) => copy(0),
+            OpcodeInstruction::Add { .. } if is(0, 0.into()) => copy(1),
+            OpcodeInstruction::Sub { .. } if is(1, 0.into()) => copy(0),
+            OpcodeInstruction::Sub { .. } if same_args => Some(Simplified::Const(0.into())),
+            OpcodeInstruction::Mul { .. } if is(1, 1.into()) => copy(0),
+            OpcodeInstruction::Mul { .. } if is(0, 1.into()) => copy(1),
+            OpcodeInstruction::Mul { .. } if is(0, 0.into()) || is(1, 0.into()) => {
+                Some(Simplified::Const(0.into()))
+            }
+            OpcodeInstruction::Div { .. } if is(1, 1.into()) => copy(0),
+            OpcodeInstruction::And { .. } if is(1, true.into()) || same_args => copy(0),
+            OpcodeInstruction::And { .. } if is(0, true.into()) => copy(1),
+            OpcodeInstruction::And { .. } if is(0, false.into()) || is(1, false.into()) => {
+                Some(Simplified::Const(false.into()))
+            }
+            OpcodeInstruction::Or { .. } if is(1, false.into()) || same_args => copy(0),
+            OpcodeInstruction::Or { .. } if is(0, false.into()) => copy(1),
+            OpcodeInstruction::Or { .. } if is(0, true.into()) || is(1, true.into()) => {
+                Some(Simplified::Const(true.into()))
+            }
+            // `not not x` is `x`
+            OpcodeInstruction::Not { .. } => match self.num_to_key.get(&arg_nums[0]) {
+                Some(ValueKey::Expr("not", _, inner_args)) => Some(Simplified::Copy(inner_args[0])),
+                _ => None,
+            },
+            // floats don't get these, nan isn't equal to itself
+            OpcodeInstruction::Eq { .. }
+            | OpcodeInstruction::Le { .. }
+            | OpcodeInstruction::Ge { .. }
+                if same_args =>
+            {
+                Some(Simplified::Const(true.into()))
+            }
+            OpcodeInstruction::Lt { .. } | OpcodeInstruction::Gt { .. } if same_args => {
+                Some(Simplified::Const(false.into()))
+            }
+            _ => None,
         }
-        OpcodeInstruction::FMul { args, dest, typ } => {
-            Some(hash_commutitative_expr(args, typ, "FMul"))
+    }
+
+    // a variable holding the value right now, preferably the one it was first computed into
+    fn get_holder(&self, num: ValueNumber) -> Option<String> {
+        let first_holder: &String = self.holders.get(&num).unwrap();
+        if self.var_to_num.get(first_holder) == Some(&num) {
+            return Some(first_holder.clone());
         }
-        _ => None,
+        self.var_to_num
+            .iter()
+            .filter(|(_, var_num)| **var_num == num)
+            .map(|(var, _)| var.clone())
+            .min()
     }
 }
 
-// perform lvn on bb that does the following optimizations:
-// CSE
+// give every destination that gets overwritten later in the bb a fresh name, and read it
+// through that name until the overwrite. values then stay around for the whole bb, while the
+// last write of every variable keeps its name so the bb still leaves its results where later
+// bbs look for them.
+fn rename_overwritten_dests(bb: &mut BasicBlock, names: &mut FreshNameGenerator) -> bool {
+    let mut changed: bool = false;
+    let mut last_def_idx: HashMap<String, usize> = HashMap::new();
+    for (inst_idx, inst) in bb.instrs.iter().enumerate() {
+        if let Some(dest) = inst.get_result() {
+            last_def_idx.insert(dest, inst_idx);
+        }
+    }
+
+    let mut renames: HashMap<String, String> = HashMap::new();
+    for (inst_idx, inst) in bb.instrs.iter_mut().enumerate() {
+        let opcode_inst: &mut OpcodeInstruction = match inst {
+            Instruction::Opcode(opcode_inst) => opcode_inst,
+            _ => continue,
+        };
+        // phi args come from other bbs
+        if let OpcodeInstruction::Phi { .. } = opcode_inst {
+            continue;
+        }
+
+        if let Some(args) = opcode_inst.get_args() {
+            for arg in args.iter_mut() {
+                if let Some(renamed) = renames.get(arg) {
+                    *arg = renamed.clone();
+                }
+            }
+        }
+        if let Some(dest) = opcode_inst.get_dest_mut() {
+            if last_def_idx[dest.as_str()] == inst_idx {
+                renames.remove(dest.as_str());
+            } else {
+                let renamed: String = names.fresh(dest);
+                renames.insert(dest.clone(), renamed.clone());
+                *dest = renamed;
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+// perform lvn on a bb, replacing recomputed values with copies of them. the table holds what's
+// known on entry to the bb, and what's known at its end when done.
 //
 // Note the pass doens't assume SSA
-fn lvn_bb(bb: &mut BasicBlock) -> bool {
-    let changed: bool;
+fn lvn_bb(bb: &mut BasicBlock, table: &mut ValueTable, names: &mut FreshNameGenerator) -> bool {
+    let mut changed: bool = rename_overwritten_dests(bb, names);
 
-    let mut expression_hash_to_value_number: HashMap<String, u32> = HashMap::new();
-    // <variable, Vec<value numbers depending on the variable>>
-    let mut variable_to_value_numbers: HashMap<String, Vec<u32>> = HashMap::new();
-    let mut value_number_to_expression: HashMap<u32, String> = HashMap::new();
-    let mut value_number_to_variable: HashMap<u32, String> = HashMap::new();
+    for inst in bb.instrs.iter_mut() {
+        let opcode_inst: &mut OpcodeInstruction = match inst {
+            Instruction::Opcode(opcode_inst) => opcode_inst,
+            _ => continue,
+        };
 
-    // build up the lvn table, good old spir-v time
-    let mut vn: u32 = 0; // counter for value number
+        // read every arg from the variable its value was first computed into, which follows
+        // copies back to where they started. phi args come from other bbs.
+        let mut arg_nums: Vec<ValueNumber> = Vec::new();
+        if !matches!(opcode_inst, OpcodeInstruction::Phi { .. }) {
+            if let Some(args) = opcode_inst.get_args() {
+                for arg in args.iter_mut() {
+                    let num: ValueNumber = table.get_var_num(arg);
+                    let holder: String = table.get_holder(num).unwrap();
+                    if *arg != holder {
+                        *arg = holder;
+                        changed = true;
+                    }
+                    arg_nums.push(num);
+                }
+            }
+        }
 
-    //
-    let mut inst_to_replace: Vec<(usize, OpcodeInstruction)> = Vec::new();
+        let dest: String = match opcode_inst.get_dest() {
+            Some(dest) => dest,
+            None => continue,
+        };
 
-    // note that we invalidate value numbers whose expression's operands are updated
-    for (inst_idx, inst) in bb.instrs.iter_mut().enumerate() {
-        match inst {
-            // only opcode insts can have rhs
-            Instruction::Opcode(opcode_inst) => {
-                // please don't look at it
-                if let Some(rhs_expr_hash) = get_rhs_hash(opcode_inst) {
-                    if let Some(opcode_inst_dest) = opcode_inst.get_dest() {
-                        if let Some(opcode_inst_type) = opcode_inst.get_type() {
-                            // found matching value member, can perform CSE
-                            if expression_hash_to_value_number.contains_key(&rhs_expr_hash) {
-                                let value_number =
-                                    expression_hash_to_value_number.get(&rhs_expr_hash).unwrap();
-                                // println!("{} maps to value number {}", rhs_expr_hash, value_number);
-                                // CSE
-                                let variable =
-                                    value_number_to_variable.get(value_number).unwrap().clone();
-                                // safe to unwrap here, trust me bro
-                                // can replace inst with an assignment
-                                let assignment_inst = OpcodeInstruction::Id {
-                                    args: vec![variable],
-                                    dest: opcode_inst_dest.clone(),
-                                    typ: opcode_inst_type,
-                                };
-                                inst_to_replace.push((inst_idx, assignment_inst));
-                                // println!("{:?}", inst_to_replace);
-                            } else {
-                                // expression not yet stored, store it as value number
-                                expression_hash_to_value_number.insert(rhs_expr_hash.clone(), vn);
-                                value_number_to_expression.insert(vn, rhs_expr_hash.clone());
-
-                                // record value number dependency on BB variables
-                                opcode_inst.get_use_list().iter().for_each(|u| {
-                                    if !variable_to_value_numbers.contains_key(u) {
-                                        variable_to_value_numbers.insert(u.clone(), Vec::new());
-                                    }
-                                    variable_to_value_numbers.get_mut(u).unwrap().push(vn)
-                                });
-                                value_number_to_variable.insert(vn, opcode_inst_dest.clone());
-                                vn += 1;
-                            }
-                        }
-                        // invalidate all value numbers that depends on the lvalue of this assign
-                        // stmt
-                        if let Some(value_numbers_to_invalidate) =
-                            variable_to_value_numbers.get_mut(&opcode_inst_dest)
-                        {
-                            // println!("Invalidating all value numbers depending on {}", opcode_inst_dest);
-                            for value_number in value_numbers_to_invalidate.iter() {
-                                // clear off existence of the value number, it's not usable anymore
-                                if let Some(expression_hash) =
-                                    value_number_to_expression.remove(value_number)
-                                {
-                                    expression_hash_to_value_number.remove(&expression_hash);
-                                }
-                                // technically since we erase the expression hash,
-                                // we'll never hit the invalid value number and use it in
-                                // value_number_to_variable to perform CSE,
-                                // but just to be safe we eliminate it altogether
-                                value_number_to_variable.remove(value_number);
-                            }
-                            value_numbers_to_invalidate.clear();
-                        }
-                    }
+        // a copy holds the same value under another name
+        if let OpcodeInstruction::Id { .. } = opcode_inst {
+            table.var_to_num.insert(dest, arg_nums[0]);
+            continue;
+        }
+
+        if ValueTable::get_key(opcode_inst, &arg_nums).is_some() {
+            match table.simplify(opcode_inst, &arg_nums) {
+                // the copied value might not be held by any variable anymore
+                Some(Simplified::Copy(num)) if table.get_holder(num).is_some() => {
+                    *opcode_inst = OpcodeInstruction::Id {
+                        args: vec![table.get_holder(num).unwrap()],
+                        dest: dest.clone(),
+                        typ: opcode_inst.get_type().unwrap(),
+                    };
+                    table.var_to_num.insert(dest, num);
+                    changed = true;
+                    continue;
+                }
+                Some(Simplified::Const(value)) => {
+                    *opcode_inst = OpcodeInstruction::Const {
+                        dest: dest.clone(),
+                        typ: opcode_inst.get_type().unwrap(),
+                        value,
+                    };
+                    changed = true;
                 }
+                _ => {}
             }
-            _ => {}
         }
+
+        let num: ValueNumber = match ValueTable::get_key(opcode_inst, &arg_nums) {
+            Some(key) => match table.key_to_num.get(&key) {
+                Some(num) => {
+                    let num: ValueNumber = *num;
+                    match table.get_holder(num) {
+                        // seen before and still around, copy it
+                        Some(holder) => {
+                            *opcode_inst = OpcodeInstruction::Id {
+                                args: vec![holder],
+                                dest: dest.clone(),
+                                typ: opcode_inst.get_type().unwrap(),
+                            };
+                            changed = true;
+                        }
+                        // every variable holding it got overwritten, this one holds it now
+                        None => table.holders.insert(num, dest.clone()),
+                    }
+                    num
+                }
+                None => {
+                    let num: ValueNumber = table.new_num(&dest);
+                    if let OpcodeInstruction::Const { value, .. } = opcode_inst {
+                        table.constants.insert(num, value.clone());
+                    }
+                    table.key_to_num.insert(key.clone(), num);
+                    table.num_to_key.insert(num, key);
+                    num
+                }
+            },
+            None => table.new_num(&dest),
+        };
+        table.var_to_num.insert(dest, num);
     }
 
-    changed = !inst_to_replace.is_empty();
+    changed
+}
 
-    for (idx, opcode_instruction) in inst_to_replace {
-        bb.instrs[idx] = ast::Instruction::Opcode(opcode_instruction);
+// number a bb and then the rest of the extended bb below it
+fn lvn_ebb(
+    bbs: &mut [BasicBlock],
+    children: &[Vec<usize>],
+    bb_idx: usize,
+    table: &mut ValueTable,
+    names: &mut FreshNameGenerator,
+) -> bool {
+    let mut changed: bool = lvn_bb(&mut bbs[bb_idx], table, names);
+    for child_idx in children[bb_idx].iter() {
+        table.push_scope();
+        changed |= lvn_ebb(bbs, children, *child_idx, table, names);
+        table.pop_scope();
     }
-
     changed
 }
 
 fn lvn_fn(function: &mut Function) -> bool {
     let mut changed: bool = false;
     let mut bbs = function.get_basic_blocks();
+    let mut names = FreshNameGenerator::new(function);
+
+    // a bb only control from its one predecessor reaches joins the predecessor's extended bb,
+    // the entry always has the function's caller as a predecessor too
+    let succs: Vec<Vec<usize>> = get_successors(&bbs);
+    let mut preds: Vec<Vec<usize>> = vec![Vec::new(); bbs.len()];
+    for (bb_idx, bb_succs) in succs.iter().enumerate() {
+        for succ_idx in bb_succs.iter() {
+            preds[*succ_idx].push(bb_idx);
+        }
+    }
+    let mut children: Vec<Vec<usize>> = vec![Vec::new(); bbs.len()];
+    let mut roots: Vec<usize> = Vec::new();
+    for (bb_idx, bb_preds) in preds.iter().enumerate() {
+        if bb_idx != 0 && bb_preds.len() == 1 {
+            children[bb_preds[0]].push(bb_idx);
+        } else {
+            roots.push(bb_idx);
+        }
+    }
 
-    for bb in bbs.iter_mut() {
-        let bb_changed = lvn_bb(bb);
-        changed |= bb_changed;
+    // bbs in a cycle of single predecessors are unreachable, no root leads to them
+    for root_idx in roots {
+        let mut table = ValueTable::new();
+        changed |= lvn_ebb(&mut bbs, &children, root_idx, &mut table, &mut names);
     }
 
     if changed {
@@ -5,3 +5,15 @@ pub mod const_prop;
 pub mod live;
 pub mod loops;
 pub mod pointer_analysis;
+pub mod out_of_ssa;
+pub mod ssa_verifier;
+pub mod sccp;
+pub mod gvn;
+pub mod copy_prop;
+pub mod adce;
+pub mod reaching_defs;
+pub mod def_use;
+pub mod cse;
+pub mod lcm;
+pub mod range;
+pub mod coalesce;
@@ -0,0 +1,449 @@
+// translation out of ssa form
+//
+// phis are replaced by parallel copies on the incoming edges. before copies are placed, phi
+// related variables whose live ranges don't interfere are coalesced into one name, so most of the
+// copies never get emitted in the first place.
+use crate::ast;
+use ast::*;
+use std::collections::{HashMap, HashSet};
+
+// copies that all happen at once, (dest, src, type)
+type ParallelCopy = Vec<(String, String, Type)>;
+
+// union-find over variable names, each class ends up sharing one name
+struct CongruenceClasses {
+    parent: HashMap<String, String>,
+    members: HashMap<String, Vec<String>>, // root -> all variables of the class
+}
+
+impl CongruenceClasses {
+    fn new() -> CongruenceClasses {
+        CongruenceClasses {
+            parent: HashMap::new(),
+            members: HashMap::new(),
+        }
+    }
+
+    fn find(&mut self, var: &str) -> String {
+        let parent = match self.parent.get(var) {
+            Some(parent) => parent.clone(),
+            None => {
+                // first time we see the variable, it's a class of its own
+                self.parent.insert(var.to_string(), var.to_string());
+                self.members.insert(var.to_string(), vec![var.to_string()]);
+                return var.to_string();
+            }
+        };
+        if parent == var {
+            return parent;
+        }
+        let root = self.find(&parent);
+        self.parent.insert(var.to_string(), root.clone());
+        root
+    }
+
+    // merge the class of `merged` into the class of `kept`, the root of `kept` names the class
+    fn union(&mut self, kept: &str, merged: &str) {
+        let kept_root = self.find(kept);
+        let merged_root = self.find(merged);
+        if kept_root == merged_root {
+            return;
+        }
+        let merged_members = self.members.remove(&merged_root).unwrap();
+        self.members
+            .get_mut(&kept_root)
+            .unwrap()
+            .extend(merged_members);
+        self.parent.insert(merged_root, kept_root);
+    }
+}
+
+// phi args used along the edge from each predecessor, keyed by (pred bb idx, bb idx).
+// the arg of a phi is used at the end of the predecessor it names, not in the phi's own bb.
+fn get_phi_edge_uses(
+    bbs: &[BasicBlock],
+    label_to_bb: &HashMap<String, usize>,
+) -> HashMap<(usize, usize), Vec<(String, String)>> {
+    // (pred, bb) -> [(phi dest, phi arg)]
+    let mut edge_uses: HashMap<(usize, usize), Vec<(String, String)>> = HashMap::new();
+    for (bb_idx, bb) in bbs.iter().enumerate() {
+        for inst in bb.instrs.iter() {
+            if let Instruction::Opcode(OpcodeInstruction::Phi {
+                args, dest, labels, ..
+            }) = inst
+            {
+                for (arg, label) in args.iter().zip(labels.iter()) {
+                    if arg == UNDEFINED_VAR {
+                        continue;
+                    }
+                    // ignore labels that don't name an actual predecessor
+                    if let Some(pred_idx) = label_to_bb.get(label) {
+                        if bb.in_bb_indices.contains(pred_idx) {
+                            edge_uses
+                                .entry((*pred_idx, bb_idx))
+                                .or_default()
+                                .push((dest.clone(), arg.clone()));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    edge_uses
+}
+
+// block-level liveness on ssa form, returns live-out sets of each bb.
+// phi dests count as defined on entry of their bb, phi args as used at the end of the pred.
+fn get_ssa_live_outs(
+    bbs: &[BasicBlock],
+    edge_uses: &HashMap<(usize, usize), Vec<(String, String)>>,
+) -> Vec<HashSet<String>> {
+    let mut upward_exposed_uses: Vec<HashSet<String>> = Vec::new();
+    let mut defs: Vec<HashSet<String>> = Vec::new();
+    for bb in bbs.iter() {
+        let mut bb_uses: HashSet<String> = HashSet::new();
+        let mut bb_defs: HashSet<String> = HashSet::new();
+        for inst in bb.instrs.iter() {
+            if !inst.is_phi() {
+                for u in inst.get_var_use_list() {
+                    if !bb_defs.contains(&u) {
+                        bb_uses.insert(u);
+                    }
+                }
+            }
+            if let Instruction::Opcode(opcode_inst) = inst {
+                if let Some(dest) = opcode_inst.get_dest() {
+                    bb_defs.insert(dest);
+                }
+            }
+        }
+        upward_exposed_uses.push(bb_uses);
+        defs.push(bb_defs);
+    }
+
+    let mut live_ins: Vec<HashSet<String>> = vec![HashSet::new(); bbs.len()];
+    let mut live_outs: Vec<HashSet<String>> = vec![HashSet::new(); bbs.len()];
+
+    // iterate until convergence, going backwards since liveness flows backwards
+    let mut changed: bool = true;
+    while changed {
+        changed = false;
+        for bb_idx in (0..bbs.len()).rev() {
+            let mut live_out: HashSet<String> = HashSet::new();
+            for succ_idx in bbs[bb_idx].out_bb_indices.iter() {
+                live_out.extend(live_ins[*succ_idx].iter().cloned());
+                if let Some(uses) = edge_uses.get(&(bb_idx, *succ_idx)) {
+                    live_out.extend(uses.iter().map(|(_, arg)| arg.clone()));
+                }
+            }
+
+            let mut live_in: HashSet<String> = upward_exposed_uses[bb_idx].clone();
+            live_in.extend(
+                live_out
+                    .iter()
+                    .filter(|var| !defs[bb_idx].contains(*var))
+                    .cloned(),
+            );
+
+            if live_in != live_ins[bb_idx] || live_out != live_outs[bb_idx] {
+                changed = true;
+                live_ins[bb_idx] = live_in;
+                live_outs[bb_idx] = live_out;
+            }
+        }
+    }
+
+    live_outs
+}
+
+// two variables interfere when one of them is live at the point the other one is defined
+fn get_interference(
+    function: &Function,
+    bbs: &[BasicBlock],
+    live_outs: &[HashSet<String>],
+) -> HashSet<(String, String)> {
+    let mut interference: HashSet<(String, String)> = HashSet::new();
+    let mut add_interference = |defined: &String, live: &HashSet<String>| {
+        for var in live.iter() {
+            if var != defined {
+                interference.insert((defined.clone(), var.clone()));
+                interference.insert((var.clone(), defined.clone()));
+            }
+        }
+    };
+
+    for (bb_idx, bb) in bbs.iter().enumerate() {
+        let mut live: HashSet<String> = live_outs[bb_idx].clone();
+        let mut phi_dests: HashSet<String> = HashSet::new();
+        for inst in bb.instrs.iter().rev() {
+            if let Instruction::Opcode(opcode_inst) = inst {
+                if inst.is_phi() {
+                    phi_dests.insert(opcode_inst.get_dest().unwrap());
+                    continue;
+                }
+                if let Some(dest) = opcode_inst.get_dest() {
+                    add_interference(&dest, &live);
+                    live.remove(&dest);
+                }
+            }
+            live.extend(inst.get_var_use_list());
+        }
+        // phis of a bb are all defined at once on entry, where everything live-in is alive
+        live.extend(phi_dests.iter().cloned());
+        for dest in phi_dests.iter() {
+            add_interference(dest, &live);
+        }
+        if bb_idx == 0 {
+            // function arguments are all defined on entry of the first bb
+            if let Some(fn_args) = &function.args {
+                live.extend(fn_args.iter().map(|arg| arg.name.clone()));
+                for arg in fn_args.iter() {
+                    add_interference(&arg.name, &live);
+                }
+            }
+        }
+    }
+
+    interference
+}
+
+// group each phi dest with its args when none of the variables involved interfere, so that
+// the copy between them disappears once everything in a class is renamed to the same name
+fn coalesce_phi_webs(
+    function: &Function,
+    bbs: &[BasicBlock],
+    interference: &HashSet<(String, String)>,
+    var_types: &HashMap<String, Type>,
+) -> CongruenceClasses {
+    let mut classes = CongruenceClasses::new();
+    let fn_arg_names: HashSet<String> = match &function.args {
+        Some(args) => args.iter().map(|arg| arg.name.clone()).collect(),
+        None => HashSet::new(),
+    };
+
+    for bb in bbs.iter() {
+        for inst in bb.instrs.iter() {
+            if let Instruction::Opcode(OpcodeInstruction::Phi { args, dest, .. }) = inst {
+                for arg in args.iter() {
+                    if var_types.get(arg) != var_types.get(dest) {
+                        continue; // undefined args and variables of different types stay apart
+                    }
+                    let dest_root = classes.find(dest);
+                    let arg_root = classes.find(arg);
+                    if dest_root == arg_root {
+                        continue;
+                    }
+                    let dest_members = &classes.members[&dest_root];
+                    let arg_members = &classes.members[&arg_root];
+                    let interferes: bool = dest_members.iter().any(|dest_member| {
+                        arg_members.iter().any(|arg_member| {
+                            interference.contains(&(dest_member.clone(), arg_member.clone()))
+                        })
+                    });
+                    if interferes {
+                        continue;
+                    }
+                    // function arguments can't be renamed, so they get to name the class
+                    if arg_members.iter().any(|var| fn_arg_names.contains(var)) {
+                        classes.union(&arg_root, &dest_root);
+                    } else {
+                        classes.union(&dest_root, &arg_root);
+                    }
+                }
+            }
+        }
+    }
+
+    classes
+}
+
+// turn a parallel copy (all sources are read before any dest is written) into a sequence of
+// `id`s. copies are emitted once their dest isn't read by a pending copy anymore; what's left
+// after that are cycles (e.g. swapping two variables), broken up by saving one dest to a temp.
+fn sequentialize_parallel_copy(
+    mut copies: ParallelCopy,
+    names: &mut FreshNameGenerator,
+) -> Vec<Instruction> {
+    let mut sequence: Vec<Instruction> = Vec::new();
+    copies.retain(|(dest, src, _)| dest != src);
+
+    while !copies.is_empty() {
+        let ready_idx = (0..copies.len()).find(|&i| {
+            let dest = &copies[i].0;
+            !copies.iter().any(|(_, src, _)| src == dest)
+        });
+        match ready_idx {
+            Some(idx) => {
+                let (dest, src, typ) = copies.remove(idx);
+                sequence.push(Instruction::Opcode(OpcodeInstruction::Id {
+                    args: vec![src],
+                    dest,
+                    typ,
+                }));
+            }
+            None => {
+                // everything left is on a cycle, save the first dest so it can be overwritten
+                let (dest, _, typ) = copies[0].clone();
+                let tmp = names.fresh(&dest);
+                sequence.push(Instruction::Opcode(OpcodeInstruction::Id {
+                    args: vec![dest.clone()],
+                    dest: tmp.clone(),
+                    typ,
+                }));
+                for (_, src, _) in copies.iter_mut() {
+                    if *src == dest {
+                        *src = tmp.clone();
+                    }
+                }
+            }
+        }
+    }
+
+    sequence
+}
+
+fn out_of_ssa_fn(function: &mut Function) -> bool {
+    if !function.instrs.iter().any(|inst| inst.is_phi()) {
+        return false;
+    }
+
+    let bbs = function.get_basic_blocks();
+    let mut names = FreshNameGenerator::new(function);
+
+    let mut label_to_bb: HashMap<String, usize> = HashMap::new();
+    for (bb_idx, bb) in bbs.iter().enumerate() {
+        if let Some(label) = bb.get_label() {
+            label_to_bb.insert(label, bb_idx);
+        }
+    }
+
+    let mut var_types: HashMap<String, Type> = HashMap::new();
+    if let Some(fn_args) = &function.args {
+        for arg in fn_args.iter() {
+            var_types.insert(arg.name.clone(), arg.arg_type.clone());
+        }
+    }
+    for inst in function.instrs.iter() {
+        if let Instruction::Opcode(opcode_inst) = inst {
+            if let (Some(dest), Some(typ)) = (opcode_inst.get_dest(), opcode_inst.get_type()) {
+                var_types.insert(dest, typ);
+            }
+        }
+    }
+
+    let edge_uses = get_phi_edge_uses(&bbs, &label_to_bb);
+    let live_outs = get_ssa_live_outs(&bbs, &edge_uses);
+    let interference = get_interference(function, &bbs, &live_outs);
+    let mut classes = coalesce_phi_webs(function, &bbs, &interference, &var_types);
+
+    // parallel copies each edge needs, after renaming to class names
+    let mut copies_to_place: Vec<((usize, usize), ParallelCopy)> = Vec::new();
+    let mut edges: Vec<&(usize, usize)> = edge_uses.keys().collect();
+    edges.sort(); // keep the output stable
+    for edge in edges {
+        let mut copies: ParallelCopy = Vec::new();
+        for (dest, arg) in edge_uses[edge].iter() {
+            let typ = var_types[dest].clone();
+            copies.push((classes.find(dest), classes.find(arg), typ));
+        }
+        copies_to_place.push((*edge, copies));
+    }
+
+    // where the copies go:
+    // 1. pred only flows into the bb: end of the pred
+    // 2. pred branches somewhere else too, but the bb has no other preds: start of the bb
+    // 3. critical edge: split it with a new bb right after the pred
+    let mut bb_heads: Vec<Vec<Instruction>> = vec![Vec::new(); bbs.len()];
+    let mut bb_tails: Vec<Vec<Instruction>> = vec![Vec::new(); bbs.len()];
+    let mut split_bbs: Vec<Vec<Instruction>> = vec![Vec::new(); bbs.len()];
+    let mut retargets: Vec<Vec<(String, String)>> = vec![Vec::new(); bbs.len()]; // (old, new)
+    for ((pred_idx, bb_idx), copies) in copies_to_place {
+        let sequence = sequentialize_parallel_copy(copies, &mut names);
+        if sequence.is_empty() {
+            continue;
+        }
+        let pred_branches: bool = matches!(
+            bbs[pred_idx].instrs.last(),
+            Some(Instruction::Opcode(OpcodeInstruction::Br { .. }))
+        );
+        if !pred_branches {
+            bb_tails[pred_idx].extend(sequence);
+        } else if bbs[bb_idx].in_bb_indices.len() == 1 {
+            bb_heads[bb_idx].extend(sequence);
+        } else {
+            let bb_label = bbs[bb_idx].get_label().unwrap();
+            let split_label = names.fresh(&format!("{}.split", bb_label));
+            split_bbs[pred_idx].push(Instruction::Label {
+                label: split_label.clone(),
+            });
+            split_bbs[pred_idx].extend(sequence);
+            split_bbs[pred_idx].push(Instruction::Opcode(OpcodeInstruction::Jmp {
+                labels: vec![bb_label.clone()],
+            }));
+            retargets[pred_idx].push((bb_label, split_label));
+        }
+    }
+
+    // stitch the function back together, dropping the phis and renaming everything to the
+    // name of its class
+    let mut instrs: Vec<Instruction> = Vec::new();
+    for (bb_idx, bb) in bbs.into_iter().enumerate() {
+        let mut bb_instrs: Vec<Instruction> = bb
+            .instrs
+            .into_iter()
+            .filter(|inst| !inst.is_phi())
+            .collect();
+
+        for inst in bb_instrs.iter_mut() {
+            if let Instruction::Opcode(opcode_inst) = inst {
+                if let Some(dest) = opcode_inst.get_dest_mut() {
+                    *dest = classes.find(dest);
+                }
+                if let Some(args) = opcode_inst.get_args() {
+                    for arg in args.iter_mut() {
+                        *arg = classes.find(arg);
+                    }
+                }
+                if let OpcodeInstruction::Br { labels, .. } = opcode_inst {
+                    for label in labels.iter_mut() {
+                        if let Some((_, new_label)) = retargets[bb_idx]
+                            .iter()
+                            .find(|(old_label, _)| old_label == label)
+                        {
+                            *label = new_label.clone();
+                        }
+                    }
+                }
+            }
+        }
+
+        let head_pos: usize = if bb_instrs.first().is_some_and(|inst| inst.is_label()) {
+            1
+        } else {
+            0
+        };
+        bb_instrs.splice(head_pos..head_pos, bb_heads[bb_idx].drain(..));
+        let tail_pos: usize = match bb_instrs.last() {
+            Some(Instruction::Opcode(OpcodeInstruction::Jmp { .. })) => bb_instrs.len() - 1,
+            _ => bb_instrs.len(),
+        };
+        bb_instrs.splice(tail_pos..tail_pos, bb_tails[bb_idx].drain(..));
+
+        instrs.append(&mut bb_instrs);
+        instrs.append(&mut split_bbs[bb_idx]);
+    }
+
+    function.instrs = instrs;
+    true
+}
+
+// replace phis with copies, bringing the program back to plain bril
+pub fn out_of_ssa_pass(program: &mut Program) -> bool {
+    let mut changed: bool = false;
+
+    for function in program.functions.iter_mut() {
+        changed |= out_of_ssa_fn(function);
+    }
+
+    changed
+}
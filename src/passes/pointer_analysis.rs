@@ -1,70 +1,90 @@
 use crate::ast;
-use crate::dom;
+use crate::dataflow;
 use ast::*;
-use dom::*;
+use dataflow::*;
 
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{HashMap, HashSet};
 
-// build a points-to graph using information from a bb
-fn build_point_to_graph(
-    bb: &BasicBlock,
-    bb_inst_offset: usize,
-    point_to_graph: &mut HashMap<String, HashSet<usize>>,
-    num_fn_insts: usize,
-) -> bool {
-    let mut changed: bool = false;
+// variable name -> allocation sites (locations in the function block) it may point to
+pub type PointsToGraph = HashMap<String, HashSet<usize>>;
 
-    for (inst_id_local, inst) in bb.instrs.iter().enumerate() {
-        let inst_id_global: usize = inst_id_local + bb_inst_offset; // function-scope instruction
-                                                                    //x = alloc n: x points to this allocations
-                                                                    //x = id y: x points to the same locations as y did
-                                                                    //x = ptradd p offset: same as id (conservative)
-                                                                    //x = load p: we aren't tracking anything about p, so x points to all memory locations
-        match inst {
-            // why don't we have cpp iterators ugh
-            Instruction::Opcode(inst) => match inst {
-                OpcodeInstruction::Alloc { args, dest, typ } => {
-                    if point_to_graph.contains_key(dest) == false {
-                        point_to_graph.insert(dest.clone(), HashSet::new());
-                    }
-                    let pointed_to: &mut HashSet<usize> = point_to_graph.get_mut(dest).unwrap();
-                    pointed_to.insert(inst_id_global);
-                    changed |= true;
-                }
-                OpcodeInstruction::Ptradd { args, dest, typ }
-                | OpcodeInstruction::Id { args, dest, typ } => {
-                    assert!(args.len() == 1 || args.len() == 2);
-                    let src_var_name = args.first().unwrap();
-                    let mut src_pointed_to: HashSet<usize> = HashSet::new();
-                    if let Some(src_pointed_to_it) = point_to_graph.get(src_var_name) {
-                        src_pointed_to = src_pointed_to_it.clone();
-                    }
+struct PointsTo {
+    bb_inst_offsets: Vec<usize>, // bb idx -> instruction offset
+    num_fn_insts: usize,         // total # of instructions
+    fn_args: Vec<String>,
+}
 
-                    if point_to_graph.contains_key(dest) == false {
-                        point_to_graph.insert(dest.clone(), HashSet::new());
-                    }
-                    let pointed_to: &mut HashSet<usize> = point_to_graph.get_mut(dest).unwrap();
-                    pointed_to.extend(src_pointed_to);
-                    changed |= true;
-                }
-                OpcodeInstruction::Load { args, dest, typ } => {
-                    if point_to_graph.contains_key(dest) == false {
-                        point_to_graph.insert(dest.clone(), HashSet::new());
-                    }
-                    let pointed_to: &mut HashSet<usize> = point_to_graph.get_mut(dest).unwrap();
-                    for i in 0..num_fn_insts {
-                        // points to everything
-                        pointed_to.insert(i);
-                    }
-                    changed |= true;
+impl Analysis for PointsTo {
+    type Fact = PointsToGraph;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn bottom(&self) -> PointsToGraph {
+        HashMap::new()
+    }
+
+    // we don't know about function arguments' aliasing --
+    // so we assume they alias with every allocation
+    fn boundary(&self) -> PointsToGraph {
+        let mut point_to_graph: PointsToGraph = HashMap::new();
+        for fn_arg in self.fn_args.iter() {
+            // push in every single code location
+            point_to_graph.insert(fn_arg.clone(), (0..self.num_fn_insts).collect());
+        }
+        point_to_graph
+    }
+
+    fn join(&self, a: &PointsToGraph, b: &PointsToGraph) -> PointsToGraph {
+        let mut joined: PointsToGraph = a.clone();
+        for (var, pointed_to) in b.iter() {
+            joined
+                .entry(var.clone())
+                .or_default()
+                .extend(pointed_to.iter().cloned());
+        }
+        joined
+    }
+
+    //x = alloc n: x points to this allocations
+    //x = id y: x points to the same locations as y did
+    //x = ptradd p offset: same as id (conservative)
+    //x = load p: we aren't tracking anything about p, so x points to all memory locations
+    fn transfer_inst(
+        &self,
+        point_to_graph: &mut PointsToGraph,
+        inst: &Instruction,
+        (bb_idx, inst_id_local): InstLocation,
+    ) {
+        let inst_id_global: usize = inst_id_local + self.bb_inst_offsets[bb_idx]; // function-scope instruction
+        match inst {
+            Instruction::Opcode(OpcodeInstruction::Alloc { dest, .. }) => {
+                point_to_graph.insert(dest.clone(), HashSet::from([inst_id_global]));
+            }
+            Instruction::Opcode(OpcodeInstruction::Ptradd { args, dest, .. })
+            | Instruction::Opcode(OpcodeInstruction::Id { args, dest, .. }) => {
+                assert!(args.len() == 1 || args.len() == 2);
+                let src_var_name = args.first().unwrap();
+                let src_pointed_to: HashSet<usize> = point_to_graph
+                    .get(src_var_name)
+                    .cloned()
+                    .unwrap_or_default();
+                point_to_graph.insert(dest.clone(), src_pointed_to);
+            }
+            Instruction::Opcode(OpcodeInstruction::Load { dest, .. }) => {
+                // points to everything
+                point_to_graph.insert(dest.clone(), (0..self.num_fn_insts).collect());
+            }
+            Instruction::Opcode(opcode_inst) => {
+                // anything else overwriting a var isn't tracked
+                if let Some(dest) = opcode_inst.get_dest() {
+                    point_to_graph.remove(&dest);
                 }
-                _ => {}
-            },
+            }
             _ => {}
         }
     }
-
-    changed
 }
 
 fn var_alias(var1: &String, var2: &String, point_to_graph: &HashMap<String, HashSet<usize>>) -> bool{
@@ -90,7 +110,7 @@ fn var_alias(var1: &String, var2: &String, point_to_graph: &HashMap<String, Hash
 
 fn dead_store_elimination_bb(
     bb: &mut BasicBlock,
-    point_to_graph: &HashMap<String, HashSet<usize>>, // var name -> memory ids var could point to
+    point_to_graphs: &[PointsToGraph], // points-to graph right before each inst
 ) -> bool {
     let mut insts_to_delete: Vec<usize> = Vec::new();
 
@@ -123,7 +143,9 @@ fn dead_store_elimination_bb(
                     for elem in unused_stores.iter() {
                         let store_dst = elem.0;
                         let _store_inst_idx = elem.1.clone();
-                        if var_alias(store_dst, load_src, point_to_graph) {
+                        // the store dst hasn't been reassigned since the store, so it still
+                        // points to the same locations here
+                        if var_alias(store_dst, load_src, &point_to_graphs[inst_idx]) {
                             used_stores.push(store_dst.clone());
                         }
                     }
@@ -150,14 +172,11 @@ fn dead_store_elimination_bb(
     changed
 }
 
-fn dead_store_elimination(
-    function: &mut Function,
-    point_to_graph: &HashMap<String, HashSet<usize>>,
-) -> bool {
+fn dead_store_elimination(function: &mut Function, point_to_graphs: &[Vec<PointsToGraph>]) -> bool {
     let mut changed: bool = false;
     let mut bbs = function.get_basic_blocks();
-    for bb in bbs.iter_mut() {
-        changed |= dead_store_elimination_bb(bb, point_to_graph);
+    for (bb_idx, bb) in bbs.iter_mut().enumerate() {
+        changed |= dead_store_elimination_bb(bb, &point_to_graphs[bb_idx]);
     }
     if changed {
         function.update(bbs);
@@ -165,13 +184,13 @@ fn dead_store_elimination(
     changed
 }
 
-fn pointer_analysis_pass_fn(function: &mut Function) -> bool {
-    let mut changed: bool = false;
-    let mut bbs = function.get_basic_blocks();
-
+// points-to graphs at every bb and instruction boundary of the function's bbs. allocation
+// sites are instruction indices counted over the whole function.
+pub fn get_points_to_graphs(
+    function: &Function,
+    bbs: &[BasicBlock],
+) -> DataflowResult<PointsToGraph> {
     // collect pointer alias info, building point-to graph
-    // variable name -> allocation site(location in the function block)
-    let mut point_to_graph: HashMap<String, HashSet<usize>> = HashMap::new();
     let mut bb_inst_offsets: Vec<usize> = Vec::new(); // bb idx -> instruction offset
 
     let num_total_insts: usize; // total # of instructions
@@ -185,49 +204,28 @@ fn pointer_analysis_pass_fn(function: &mut Function) -> bool {
         num_total_insts = offset;
     }
 
-    // we don't know about function arguments' aliasing --
-    // so we assume they alias with every allocation
-    if let Some(fn_args) = &function.args {
-        for fn_arg in fn_args.iter() {
-            point_to_graph.insert(fn_arg.name.clone(), HashSet::new());
-            // push in every single code location
-            let locations: &mut HashSet<usize> = point_to_graph.get_mut(&fn_arg.name).unwrap();
-            for loc in 0..num_total_insts {
-                locations.insert(loc);
-            }
-        }
-    }
-
-    let mut wl: VecDeque<usize> = VecDeque::new();
-    let mut in_wl: HashSet<usize> = HashSet::new();
-    // initialize wl with all bbs
-    for i in 0..bbs.len() {
-        wl.push_back(i);
-        in_wl.insert(i);
-    }
+    let analysis = PointsTo {
+        bb_inst_offsets,
+        num_fn_insts: num_total_insts,
+        fn_args: function
+            .args
+            .iter()
+            .flatten()
+            .map(|fn_arg| fn_arg.name.clone())
+            .collect(),
+    };
 
     // perform forward analysis
-    // processing wl, pushing back onto wl on change
-    while let Some(bb_idx) = wl.pop_front() {
-        let bb: &BasicBlock;
-        unsafe {
-            bb = bbs.get_unchecked_mut(bb_idx);
-        }
-        let inst_offset = bb_inst_offsets.get(bb_idx).unwrap().clone();
-        let point_to_graph_changed: bool =
-            build_point_to_graph(bb, inst_offset, &mut point_to_graph, num_total_insts);
-        if point_to_graph_changed {
-            for child in bb.out_bb_indices.iter() {
-                if in_wl.contains(child) == false {
-                    wl.push_back(child.clone());
-                    in_wl.insert(child.clone());
-                }
-            }
-        }
-    }
+    solve(&analysis, bbs)
+}
+
+fn pointer_analysis_pass_fn(function: &mut Function) -> bool {
+    let mut changed: bool = false;
+    let bbs = function.get_basic_blocks();
+    let result = get_points_to_graphs(function, &bbs);
 
     // done building points-to graph, now perform optimizations
-    changed |= dead_store_elimination(function, &point_to_graph);
+    changed |= dead_store_elimination(function, &result.inst_in);
 
     changed
 }
@@ -0,0 +1,455 @@
+// value range analysis
+//
+// every int variable gets an interval of the values it may hold, bools are tracked the same
+// way as 0 or 1. branches narrow the intervals of what their condition compared along each
+// arm, and loop headers widen whatever bound keeps moving so loops settle. comparisons and
+// branches the intervals decide are folded.
+use crate::ast::*;
+use crate::dataflow::*;
+use crate::dom::get_successors;
+use crate::passes::const_prop::{get_stable_def, remove_unreachable_bbs};
+use crate::passes::loops::get_natural_loops;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Interval {
+    pub lo: i64,
+    pub hi: i64,
+}
+
+const FULL: Interval = Interval {
+    lo: i64::MIN,
+    hi: i64::MAX,
+};
+const BOOL: Interval = Interval { lo: 0, hi: 1 };
+const TRUE: Interval = Interval { lo: 1, hi: 1 };
+const FALSE: Interval = Interval { lo: 0, hi: 0 };
+
+impl Interval {
+    // ints wrap around, a bound past the end of i64 means the value could be anything
+    fn new(lo: i128, hi: i128) -> Interval {
+        if lo < i64::MIN as i128 || hi > i64::MAX as i128 {
+            FULL
+        } else {
+            Interval {
+                lo: lo as i64,
+                hi: hi as i64,
+            }
+        }
+    }
+
+    fn constant(value: i64) -> Interval {
+        Interval {
+            lo: value,
+            hi: value,
+        }
+    }
+
+    fn hull(&self, other: &Interval) -> Interval {
+        Interval {
+            lo: self.lo.min(other.lo),
+            hi: self.hi.max(other.hi),
+        }
+    }
+
+    // `None` if nothing is in both
+    fn intersect(&self, other: &Interval) -> Option<Interval> {
+        let lo: i64 = self.lo.max(other.lo);
+        let hi: i64 = self.hi.min(other.hi);
+        if lo <= hi {
+            Some(Interval { lo, hi })
+        } else {
+            None
+        }
+    }
+
+    // the interval of `op` applied to every pair of values, `op` has to take its extremes at
+    // the corners
+    fn corners(&self, other: &Interval, op: fn(i128, i128) -> i128) -> Interval {
+        let values: Vec<i128> = vec![
+            op(self.lo as i128, other.lo as i128),
+            op(self.lo as i128, other.hi as i128),
+            op(self.hi as i128, other.lo as i128),
+            op(self.hi as i128, other.hi as i128),
+        ];
+        Interval::new(*values.iter().min().unwrap(), *values.iter().max().unwrap())
+    }
+}
+
+fn from_bool(value: bool) -> Interval {
+    if value {
+        TRUE
+    } else {
+        FALSE
+    }
+}
+
+// variable -> interval, variables missing are undefined or not ints or bools
+#[derive(Clone, PartialEq)]
+pub struct RangeState {
+    pub ranges: HashMap<String, Interval>,
+}
+
+fn get_top(typ: &Type) -> Option<Interval> {
+    match typ {
+        Type::Primitive(name) if name == "int" => Some(FULL),
+        Type::Primitive(name) if name == "bool" => Some(BOOL),
+        _ => None,
+    }
+}
+
+// whether `op` holds for every pair of values, or for none of them
+fn compare(a: &Interval, b: &Interval, op: &OpcodeInstruction) -> Interval {
+    let (always, never): (bool, bool) = match op {
+        OpcodeInstruction::Lt { .. } => (a.hi < b.lo, a.lo >= b.hi),
+        OpcodeInstruction::Le { .. } => (a.hi <= b.lo, a.lo > b.hi),
+        OpcodeInstruction::Gt { .. } => (a.lo > b.hi, a.hi <= b.lo),
+        OpcodeInstruction::Ge { .. } => (a.lo >= b.hi, a.hi < b.lo),
+        OpcodeInstruction::Eq { .. } => (a.lo == a.hi && *a == *b, a.intersect(b).is_none()),
+        _ => (false, false),
+    };
+    if always {
+        TRUE
+    } else if never {
+        FALSE
+    } else {
+        BOOL
+    }
+}
+
+// the interval of an instruction's dest, `None` while one of its args is still undefined
+fn evaluate(opcode_inst: &OpcodeInstruction, ctx: &RangeState) -> Option<Interval> {
+    let top: Interval = get_top(&opcode_inst.get_type()?)?;
+    let args: Vec<String> = opcode_inst.get_var_use_list();
+    let get = |arg_idx: usize| ctx.ranges.get(&args[arg_idx]);
+    let interval: Interval = match opcode_inst {
+        OpcodeInstruction::Const { value, .. } => match value {
+            Value::Bool(value) => from_bool(*value),
+            _ => Interval::constant(value.as_i64()?),
+        },
+        OpcodeInstruction::Id { .. } => *get(0)?,
+        OpcodeInstruction::Add { .. } => get(0)?.corners(get(1)?, |a, b| a + b),
+        OpcodeInstruction::Sub { .. } => get(0)?.corners(get(1)?, |a, b| a - b),
+        OpcodeInstruction::Mul { .. } => get(0)?.corners(get(1)?, |a, b| a * b),
+        // truncating division is monotonic in both args as long as the divisor keeps its sign
+        OpcodeInstruction::Div { .. } => {
+            let (a, b) = (get(0)?, get(1)?);
+            if b.lo > 0 || b.hi < 0 {
+                a.corners(b, |a, b| a / b)
+            } else {
+                top
+            }
+        }
+        OpcodeInstruction::Eq { .. }
+        | OpcodeInstruction::Lt { .. }
+        | OpcodeInstruction::Le { .. }
+        | OpcodeInstruction::Gt { .. }
+        | OpcodeInstruction::Ge { .. } => compare(get(0)?, get(1)?, opcode_inst),
+        OpcodeInstruction::Not { .. } => {
+            let a: &Interval = get(0)?;
+            Interval {
+                lo: 1 - a.hi,
+                hi: 1 - a.lo,
+            }
+        }
+        // on 0 and 1, `and` is the min and `or` the max
+        OpcodeInstruction::And { .. } => {
+            let (a, b) = (get(0)?, get(1)?);
+            Interval {
+                lo: a.lo.min(b.lo),
+                hi: a.hi.min(b.hi),
+            }
+        }
+        OpcodeInstruction::Or { .. } => {
+            let (a, b) = (get(0)?, get(1)?);
+            Interval {
+                lo: a.lo.max(b.lo),
+                hi: a.hi.max(b.hi),
+            }
+        }
+        OpcodeInstruction::Phi { .. } => args
+            .iter()
+            .filter_map(|arg| ctx.ranges.get(arg))
+            .cloned()
+            .reduce(|a, b| a.hull(&b))?,
+        // calls, loads, float comparisons
+        _ => top,
+    };
+    Some(interval)
+}
+
+// narrow `var` down to `interval`, false if the two don't overlap
+fn narrow(ctx: &mut RangeState, var: &str, interval: Interval) -> bool {
+    match ctx.ranges.get(var) {
+        Some(known) => match known.intersect(&interval) {
+            Some(narrowed) => {
+                ctx.ranges.insert(var.to_string(), narrowed);
+                true
+            }
+            None => false,
+        },
+        None => true,
+    }
+}
+
+// narrow `a` and `b` down to the values where `a < b` (or `a <= b`) holds
+fn narrow_less(ctx: &mut RangeState, a: &str, b: &str, strict: bool) -> bool {
+    let (a_range, b_range) = match (ctx.ranges.get(a), ctx.ranges.get(b)) {
+        (Some(a_range), Some(b_range)) => (*a_range, *b_range),
+        _ => return true,
+    };
+    let gap: i128 = if strict { 1 } else { 0 };
+    let a_hi: i128 = b_range.hi as i128 - gap;
+    let b_lo: i128 = a_range.lo as i128 + gap;
+    if a_hi < i64::MIN as i128 || b_lo > i64::MAX as i128 {
+        return false;
+    }
+    narrow(ctx, a, Interval::new(i64::MIN as i128, a_hi))
+        && narrow(ctx, b, Interval::new(b_lo, i64::MAX as i128))
+}
+
+// `var` is known to be the bool `value` at the end of `instrs`, narrow what it was computed
+// from. false if that can't happen.
+fn narrow_condition(ctx: &mut RangeState, instrs: &[Instruction], var: &str, value: bool) -> bool {
+    if !narrow(ctx, var, from_bool(value)) {
+        return false;
+    }
+    let def: &OpcodeInstruction = match get_stable_def(instrs, var) {
+        Some(def) => def,
+        None => return true,
+    };
+    let args: Vec<String> = def.get_var_use_list();
+    let (a, b) = match args.as_slice() {
+        [a, b] => (a.as_str(), b.as_str()),
+        [a] => (a.as_str(), a.as_str()),
+        _ => return true,
+    };
+    match (def, value) {
+        (OpcodeInstruction::Id { .. }, _) => narrow_condition(ctx, instrs, a, value),
+        (OpcodeInstruction::Not { .. }, _) => narrow_condition(ctx, instrs, a, !value),
+        (OpcodeInstruction::And { .. }, true) | (OpcodeInstruction::Or { .. }, false) => {
+            narrow_condition(ctx, instrs, a, value) && narrow_condition(ctx, instrs, b, value)
+        }
+        (OpcodeInstruction::Lt { .. }, true) | (OpcodeInstruction::Ge { .. }, false) => {
+            narrow_less(ctx, a, b, true)
+        }
+        (OpcodeInstruction::Lt { .. }, false) | (OpcodeInstruction::Ge { .. }, true) => {
+            narrow_less(ctx, b, a, false)
+        }
+        (OpcodeInstruction::Le { .. }, true) | (OpcodeInstruction::Gt { .. }, false) => {
+            narrow_less(ctx, a, b, false)
+        }
+        (OpcodeInstruction::Le { .. }, false) | (OpcodeInstruction::Gt { .. }, true) => {
+            narrow_less(ctx, b, a, true)
+        }
+        (OpcodeInstruction::Eq { .. }, true) => {
+            let (a_range, b_range) = match (ctx.ranges.get(a), ctx.ranges.get(b)) {
+                (Some(a_range), Some(b_range)) => (*a_range, *b_range),
+                _ => return true,
+            };
+            narrow(ctx, a, b_range) && narrow(ctx, b, a_range)
+        }
+        _ => true,
+    }
+}
+
+struct RangeAnalysis {
+    entry_state: RangeState,
+    loop_headers: HashSet<usize>,
+}
+
+impl Analysis for RangeAnalysis {
+    type Fact = RangeState;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn bottom(&self) -> RangeState {
+        RangeState {
+            ranges: HashMap::new(),
+        }
+    }
+
+    fn boundary(&self) -> RangeState {
+        self.entry_state.clone()
+    }
+
+    fn join(&self, a: &RangeState, b: &RangeState) -> RangeState {
+        let mut joined: RangeState = a.clone();
+        for (var, interval) in b.ranges.iter() {
+            let joined_interval: Interval = match joined.ranges.get(var) {
+                Some(known) => known.hull(interval),
+                None => *interval,
+            };
+            joined.ranges.insert(var.clone(), joined_interval);
+        }
+        joined
+    }
+
+    // bounds still moving at a loop header are given up on. bools can only move so far, they
+    // are left alone. nothing known before is dropped either, so headers only ever grow.
+    fn widen(&self, previous: &RangeState, next: RangeState, bb_idx: usize) -> RangeState {
+        if !self.loop_headers.contains(&bb_idx) {
+            return next;
+        }
+        let mut widened: RangeState = previous.clone();
+        for (var, next_interval) in next.ranges.iter() {
+            let previous_interval: &Interval = match previous.ranges.get(var) {
+                Some(previous_interval) => previous_interval,
+                None => {
+                    widened.ranges.insert(var.clone(), *next_interval);
+                    continue;
+                }
+            };
+            let hull: Interval = previous_interval.hull(next_interval);
+            let interval: Interval = if BOOL.intersect(&hull) == Some(hull) {
+                hull
+            } else {
+                Interval {
+                    lo: if next_interval.lo < previous_interval.lo {
+                        i64::MIN
+                    } else {
+                        previous_interval.lo
+                    },
+                    hi: if next_interval.hi > previous_interval.hi {
+                        i64::MAX
+                    } else {
+                        previous_interval.hi
+                    },
+                }
+            };
+            widened.ranges.insert(var.clone(), interval);
+        }
+        widened
+    }
+
+    // the arm of a branch knows which way its condition went, an arm that can't be taken
+    // gets nothing through
+    fn transfer_edge(&self, ctx: &mut RangeState, bbs: &[BasicBlock], from: usize, to: usize) {
+        let instrs: &[Instruction] = &bbs[from].instrs;
+        if let Some(Instruction::Opcode(OpcodeInstruction::Br { args, labels })) = instrs.last() {
+            if labels[0] == labels[1] {
+                return;
+            }
+            if let Some(to_label) = bbs[to].get_label() {
+                if labels.contains(&to_label)
+                    && !narrow_condition(ctx, instrs, &args[0], to_label == labels[0])
+                {
+                    ctx.ranges.clear();
+                }
+            }
+        }
+    }
+
+    fn transfer_inst(&self, ctx: &mut RangeState, inst: &Instruction, _: InstLocation) {
+        if let Instruction::Opcode(opcode_inst) = inst {
+            if let Some(dest) = opcode_inst.get_dest() {
+                match evaluate(opcode_inst, ctx) {
+                    Some(interval) => {
+                        ctx.ranges.insert(dest, interval);
+                    }
+                    None => {
+                        ctx.ranges.remove(&dest);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// intervals of every variable at every bb and instruction boundary of the function's bbs
+pub fn get_ranges(function: &Function, bbs: &[BasicBlock]) -> DataflowResult<RangeState> {
+    let mut entry_state: RangeState = RangeState {
+        ranges: HashMap::new(),
+    };
+    if let Some(args) = &function.args {
+        for arg in args.iter() {
+            if let Some(top) = get_top(&arg.arg_type) {
+                entry_state.ranges.insert(arg.name.clone(), top);
+            }
+        }
+    }
+    // every cycle goes backwards through the layout somewhere, widening where it does also
+    // covers the loops that aren't natural ones
+    let mut loop_headers: HashSet<usize> = get_natural_loops(bbs)
+        .iter()
+        .map(|loop_| loop_.header_idx)
+        .collect();
+    for (bb_idx, bb_succs) in get_successors(bbs).iter().enumerate() {
+        loop_headers.extend(bb_succs.iter().filter(|succ_idx| **succ_idx <= bb_idx));
+    }
+    let analysis = RangeAnalysis {
+        entry_state,
+        loop_headers,
+    };
+    solve(&analysis, bbs)
+}
+
+fn value_range_fn(function: &mut Function) -> bool {
+    let mut bbs = function.get_basic_blocks();
+    let result = get_ranges(function, &bbs);
+
+    let mut changed: bool = false;
+    let mut folded_branch: bool = false;
+    for (bb_idx, bb) in bbs.iter_mut().enumerate() {
+        for (inst_idx, inst) in bb.instrs.iter_mut().enumerate() {
+            let opcode_inst: &mut OpcodeInstruction = match inst {
+                Instruction::Opcode(opcode_inst) => opcode_inst,
+                _ => continue,
+            };
+            match opcode_inst {
+                OpcodeInstruction::Eq { dest, typ, .. }
+                | OpcodeInstruction::Lt { dest, typ, .. }
+                | OpcodeInstruction::Le { dest, typ, .. }
+                | OpcodeInstruction::Gt { dest, typ, .. }
+                | OpcodeInstruction::Ge { dest, typ, .. } => {
+                    let interval: Option<&Interval> =
+                        result.inst_out[bb_idx][inst_idx].ranges.get(dest);
+                    if let Some(Interval { lo, hi }) = interval {
+                        if lo == hi {
+                            *opcode_inst = OpcodeInstruction::Const {
+                                dest: dest.clone(),
+                                typ: typ.clone(),
+                                value: (*lo == 1).into(),
+                            };
+                            changed = true;
+                        }
+                    }
+                }
+                OpcodeInstruction::Br { args, labels } => {
+                    let interval: Option<&Interval> =
+                        result.inst_in[bb_idx][inst_idx].ranges.get(&args[0]);
+                    if let Some(Interval { lo, hi }) = interval {
+                        if lo == hi {
+                            let taken = if *lo == 1 { &labels[0] } else { &labels[1] };
+                            *opcode_inst = OpcodeInstruction::Jmp {
+                                labels: vec![taken.clone()],
+                            };
+                            changed = true;
+                            folded_branch = true;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if changed {
+        function.update(bbs);
+    }
+    if folded_branch {
+        remove_unreachable_bbs(function);
+    }
+    changed
+}
+
+pub fn value_range_pass(program: &mut Program) -> bool {
+    let mut changed: bool = false;
+    for function in program.functions.iter_mut() {
+        changed |= value_range_fn(function);
+    }
+    changed
+}
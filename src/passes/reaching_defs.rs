@@ -0,0 +1,140 @@
+// reaching definitions
+//
+// a definition of `x` reaches a point if some path from it to the point doesn't redefine `x`.
+// definitions are instructions, function arguments count as definitions made on entry.
+use crate::ast;
+use crate::dataflow;
+use ast::*;
+use dataflow::*;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Definition {
+    Argument(usize),    // index into the function's args
+    Inst(InstLocation), // instruction with a dest
+}
+
+struct ReachingDefsAnalysis {
+    num_args: usize,
+    // every definition of a variable
+    defs_of_var: HashMap<String, HashSet<Definition>>,
+}
+
+impl Analysis for ReachingDefsAnalysis {
+    type Fact = HashSet<Definition>;
+
+    fn direction(&self) -> Direction {
+        Direction::Forward
+    }
+
+    fn bottom(&self) -> HashSet<Definition> {
+        HashSet::new()
+    }
+
+    fn boundary(&self) -> HashSet<Definition> {
+        (0..self.num_args).map(Definition::Argument).collect()
+    }
+
+    fn join(&self, a: &HashSet<Definition>, b: &HashSet<Definition>) -> HashSet<Definition> {
+        a.union(b).cloned().collect()
+    }
+
+    // a definition kills every other definition of the same variable
+    fn transfer_inst(
+        &self,
+        reaching: &mut HashSet<Definition>,
+        inst: &Instruction,
+        location: InstLocation,
+    ) {
+        if let Instruction::Opcode(opcode_inst) = inst {
+            if let Some(dest) = opcode_inst.get_dest() {
+                let killed: &HashSet<Definition> = &self.defs_of_var[&dest];
+                reaching.retain(|def| !killed.contains(def));
+                reaching.insert(Definition::Inst(location));
+            }
+        }
+    }
+}
+
+pub struct ReachingDefinitions {
+    // variable each definition defines
+    def_vars: HashMap<Definition, String>,
+    result: DataflowResult<HashSet<Definition>>,
+}
+
+impl ReachingDefinitions {
+    // `bbs` has to come from `function.get_basic_blocks()`, locations index into them
+    pub fn new(function: &Function, bbs: &[BasicBlock]) -> ReachingDefinitions {
+        let mut def_vars: HashMap<Definition, String> = HashMap::new();
+        let mut num_args: usize = 0;
+        if let Some(fn_args) = &function.args {
+            for (arg_idx, arg) in fn_args.iter().enumerate() {
+                def_vars.insert(Definition::Argument(arg_idx), arg.name.clone());
+            }
+            num_args = fn_args.len();
+        }
+        for (bb_idx, bb) in bbs.iter().enumerate() {
+            for (inst_idx, inst) in bb.instrs.iter().enumerate() {
+                if let Instruction::Opcode(opcode_inst) = inst {
+                    if let Some(dest) = opcode_inst.get_dest() {
+                        def_vars.insert(Definition::Inst((bb_idx, inst_idx)), dest);
+                    }
+                }
+            }
+        }
+
+        let mut defs_of_var: HashMap<String, HashSet<Definition>> = HashMap::new();
+        for (def, var) in def_vars.iter() {
+            defs_of_var.entry(var.clone()).or_default().insert(*def);
+        }
+
+        let analysis = ReachingDefsAnalysis {
+            num_args,
+            defs_of_var,
+        };
+        ReachingDefinitions {
+            result: solve(&analysis, bbs),
+            def_vars,
+        }
+    }
+
+    // the variable a definition defines
+    pub fn get_var(&self, def: &Definition) -> &String {
+        &self.def_vars[def]
+    }
+
+    // every definition reaching right before the instruction
+    pub fn reaching_in(&self, (bb_idx, inst_idx): InstLocation) -> &HashSet<Definition> {
+        &self.result.inst_in[bb_idx][inst_idx]
+    }
+
+    // every definition reaching right after the instruction
+    pub fn reaching_out(&self, (bb_idx, inst_idx): InstLocation) -> &HashSet<Definition> {
+        &self.result.inst_out[bb_idx][inst_idx]
+    }
+
+    // every definition reaching the end of the bb, which is what phis in its successors see
+    pub fn reaching_bb_out(&self, bb_idx: usize) -> &HashSet<Definition> {
+        &self.result.bb_out[bb_idx]
+    }
+
+    fn filter_var(&self, reaching: &HashSet<Definition>, var: &str) -> Vec<Definition> {
+        let mut defs: Vec<Definition> = reaching
+            .iter()
+            .filter(|def| self.def_vars[*def] == var)
+            .cloned()
+            .collect();
+        defs.sort();
+        defs
+    }
+
+    // definitions of `var` that can reach the instruction, in program order
+    pub fn get_reaching_defs(&self, var: &str, location: InstLocation) -> Vec<Definition> {
+        self.filter_var(self.reaching_in(location), var)
+    }
+
+    // definitions of `var` that can reach the end of the bb, in program order
+    pub fn get_reaching_defs_at_bb_end(&self, var: &str, bb_idx: usize) -> Vec<Definition> {
+        self.filter_var(self.reaching_bb_out(bb_idx), var)
+    }
+}
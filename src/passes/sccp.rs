@@ -0,0 +1,347 @@
+// sparse conditional constant propagation (wegman & zadeck)
+//
+// only bbs reachable through edges proven executable are evaluated, and values only flow
+// into phis along executable edges. on ssa input every variable has one definition; plain
+// bril works too, a variable then takes the meet of all of its definitions in executable bbs.
+use crate::ast;
+use crate::const_eval::fold;
+use ast::*;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+#[derive(Clone, PartialEq, Debug)]
+enum LatticeValue {
+    Undefined, // no executable definition seen yet
+    Constant(Value),
+    Overdefined, // can't be proven constant
+}
+
+fn meet(a: &LatticeValue, b: &LatticeValue) -> LatticeValue {
+    match (a, b) {
+        (LatticeValue::Undefined, other) | (other, LatticeValue::Undefined) => other.clone(),
+        (LatticeValue::Constant(x), LatticeValue::Constant(y)) if x == y => a.clone(),
+        _ => LatticeValue::Overdefined,
+    }
+}
+
+struct SccpState {
+    label_to_bb: HashMap<String, usize>,
+    args: HashSet<String>, // function arguments, defined on entry with unknown values
+    defs: HashMap<String, Vec<(usize, usize)>>, // variable -> (bb idx, inst idx) defining it
+    uses: HashMap<String, Vec<(usize, usize)>>, // variable -> (bb idx, inst idx) using it
+    def_values: HashMap<(usize, usize), LatticeValue>, // value each definition produces
+    var_values: HashMap<String, LatticeValue>,  // meet of the values of all definitions
+    executable_bbs: HashSet<usize>,
+    executable_edges: HashSet<(usize, usize)>,
+    flow_work_list: VecDeque<(usize, usize)>, // edges that just became executable
+    ssa_work_list: VecDeque<(usize, usize)>,  // instructions whose operands changed
+}
+
+impl SccpState {
+    fn get_value(&self, var: &String) -> LatticeValue {
+        match self.var_values.get(var) {
+            Some(value) => value.clone(),
+            None => LatticeValue::Undefined,
+        }
+    }
+
+    fn mark_edge(&mut self, from: usize, to: usize) {
+        if self.executable_edges.insert((from, to)) {
+            self.flow_work_list.push_back((from, to));
+        }
+    }
+
+    // whether control can flow into `bb_idx` from the bb labelled `label`
+    fn is_edge_executable(&self, label: &String, bb_idx: usize) -> bool {
+        match self.label_to_bb.get(label) {
+            Some(pred_idx) => self.executable_edges.contains(&(*pred_idx, bb_idx)),
+            None => false,
+        }
+    }
+
+    fn evaluate(&self, opcode_inst: &OpcodeInstruction, bb_idx: usize) -> LatticeValue {
+        match opcode_inst {
+            OpcodeInstruction::Const { value, .. } => LatticeValue::Constant(value.clone()),
+            OpcodeInstruction::Phi { args, labels, .. } => {
+                let mut value = LatticeValue::Undefined;
+                for (arg, label) in args.iter().zip(labels.iter()) {
+                    if arg != UNDEFINED_VAR && self.is_edge_executable(label, bb_idx) {
+                        value = meet(&value, &self.get_value(arg));
+                    }
+                }
+                value
+            }
+            OpcodeInstruction::And { args, .. } | OpcodeInstruction::Or { args, .. } => {
+                // one constant arg can decide these on its own
+                let absorbing: Value = matches!(opcode_inst, OpcodeInstruction::Or { .. }).into();
+                let values: Vec<LatticeValue> =
+                    args.iter().map(|arg| self.get_value(arg)).collect();
+                if values.contains(&LatticeValue::Constant(absorbing.clone())) {
+                    LatticeValue::Constant(absorbing)
+                } else {
+                    self.evaluate_args(opcode_inst, values)
+                }
+            }
+            _ => {
+                if !Instruction::Opcode(opcode_inst.clone()).has_no_side_effects() {
+                    return LatticeValue::Overdefined;
+                }
+                let values: Vec<LatticeValue> = opcode_inst
+                    .get_var_use_list()
+                    .iter()
+                    .map(|arg| self.get_value(arg))
+                    .collect();
+                self.evaluate_args(opcode_inst, values)
+            }
+        }
+    }
+
+    fn evaluate_args(
+        &self,
+        opcode_inst: &OpcodeInstruction,
+        values: Vec<LatticeValue>,
+    ) -> LatticeValue {
+        let mut constants: Vec<Value> = Vec::new();
+        let mut undefined: bool = false;
+        for value in values {
+            match value {
+                LatticeValue::Overdefined => return LatticeValue::Overdefined,
+                LatticeValue::Undefined => undefined = true,
+                LatticeValue::Constant(constant) => constants.push(constant),
+            }
+        }
+        if undefined {
+            return LatticeValue::Undefined;
+        }
+        match fold(opcode_inst, &constants) {
+            Some(value) => LatticeValue::Constant(value),
+            None => LatticeValue::Overdefined,
+        }
+    }
+
+    fn visit_inst(&mut self, bbs: &[BasicBlock], bb_idx: usize, inst_idx: usize) {
+        let opcode_inst = match &bbs[bb_idx].instrs[inst_idx] {
+            Instruction::Opcode(opcode_inst) => opcode_inst,
+            _ => return,
+        };
+
+        if let OpcodeInstruction::Br { args, labels } = opcode_inst {
+            let targets: Vec<&String> = match self.get_value(&args[0]) {
+                LatticeValue::Undefined => Vec::new(),
+                LatticeValue::Constant(Value::Bool(true)) => vec![&labels[0]],
+                LatticeValue::Constant(Value::Bool(false)) => vec![&labels[1]],
+                _ => labels.iter().collect(),
+            };
+            for target in targets {
+                if let Some(target_idx) = self.label_to_bb.get(target) {
+                    self.mark_edge(bb_idx, *target_idx);
+                }
+            }
+            return;
+        }
+
+        let dest = match opcode_inst.get_dest() {
+            Some(dest) => dest,
+            None => return,
+        };
+        let value = self.evaluate(opcode_inst, bb_idx);
+        if self.def_values.get(&(bb_idx, inst_idx)) == Some(&value) {
+            return;
+        }
+        self.def_values.insert((bb_idx, inst_idx), value);
+
+        // the variable is the meet of all its definitions evaluated so far, an argument that
+        // gets reassigned still holds its unknown value up to the reassignment
+        let mut var_value = if self.args.contains(&dest) {
+            LatticeValue::Overdefined
+        } else {
+            LatticeValue::Undefined
+        };
+        for def in self.defs[&dest].iter() {
+            if let Some(def_value) = self.def_values.get(def) {
+                var_value = meet(&var_value, def_value);
+            }
+        }
+        if self.get_value(&dest) != var_value {
+            self.var_values.insert(dest.clone(), var_value);
+            if let Some(uses) = self.uses.get(&dest) {
+                self.ssa_work_list.extend(uses.iter().cloned());
+            }
+        }
+    }
+
+    fn visit_bb(&mut self, bbs: &[BasicBlock], bb_idx: usize) {
+        let bb = &bbs[bb_idx];
+        for inst_idx in 0..bb.instrs.len() {
+            self.visit_inst(bbs, bb_idx, inst_idx);
+        }
+        // br marks its own edges, `ret` leaves the function, everything else flows on
+        let ends_with_br: bool = matches!(
+            bb.instrs.last(),
+            Some(Instruction::Opcode(OpcodeInstruction::Br { .. }))
+        );
+        let returns: bool = bb
+            .instrs
+            .iter()
+            .any(|inst| matches!(inst, Instruction::Opcode(OpcodeInstruction::Ret { .. })));
+        if !ends_with_br && !returns {
+            for succ_idx in bb.out_bb_indices.iter() {
+                self.mark_edge(bb_idx, *succ_idx);
+            }
+        }
+    }
+}
+
+fn sccp_fn(function: &mut Function) -> bool {
+    let mut bbs = function.get_basic_blocks();
+    if bbs.is_empty() {
+        return false;
+    }
+
+    let mut state = SccpState {
+        label_to_bb: HashMap::new(),
+        args: HashSet::new(),
+        defs: HashMap::new(),
+        uses: HashMap::new(),
+        def_values: HashMap::new(),
+        var_values: HashMap::new(),
+        executable_bbs: HashSet::new(),
+        executable_edges: HashSet::new(),
+        flow_work_list: VecDeque::new(),
+        ssa_work_list: VecDeque::new(),
+    };
+
+    for (bb_idx, bb) in bbs.iter().enumerate() {
+        if let Some(label) = bb.get_label() {
+            state.label_to_bb.insert(label, bb_idx);
+        }
+        for (inst_idx, inst) in bb.instrs.iter().enumerate() {
+            for var in inst.get_var_use_list() {
+                state.uses.entry(var).or_default().push((bb_idx, inst_idx));
+            }
+            if let Instruction::Opcode(opcode_inst) = inst {
+                if let Some(dest) = opcode_inst.get_dest() {
+                    state.defs.entry(dest).or_default().push((bb_idx, inst_idx));
+                }
+            }
+        }
+    }
+    // nothing is known about the arguments
+    if let Some(fn_args) = &function.args {
+        for arg in fn_args.iter() {
+            state.args.insert(arg.name.clone());
+            state
+                .var_values
+                .insert(arg.name.clone(), LatticeValue::Overdefined);
+        }
+    }
+
+    // the entry bb is executable to begin with
+    state.executable_bbs.insert(0);
+    state.visit_bb(&bbs, 0);
+
+    while !state.flow_work_list.is_empty() || !state.ssa_work_list.is_empty() {
+        if let Some((_, bb_idx)) = state.flow_work_list.pop_front() {
+            if state.executable_bbs.insert(bb_idx) {
+                state.visit_bb(&bbs, bb_idx);
+            } else {
+                // only the phis can see the new edge
+                for inst_idx in 0..bbs[bb_idx].instrs.len() {
+                    if bbs[bb_idx].instrs[inst_idx].is_phi() {
+                        state.visit_inst(&bbs, bb_idx, inst_idx);
+                    }
+                }
+            }
+        } else if let Some((bb_idx, inst_idx)) = state.ssa_work_list.pop_front() {
+            if state.executable_bbs.contains(&bb_idx) {
+                state.visit_inst(&bbs, bb_idx, inst_idx);
+            }
+        }
+    }
+
+    let mut changed: bool = false;
+    for (bb_idx, bb) in bbs.iter_mut().enumerate() {
+        if !state.executable_bbs.contains(&bb_idx) {
+            continue;
+        }
+        for (inst_idx, inst) in bb.instrs.iter_mut().enumerate() {
+            let opcode_inst = match inst {
+                Instruction::Opcode(opcode_inst) => opcode_inst,
+                _ => continue,
+            };
+            match opcode_inst {
+                // constant conditions turn into jumps
+                OpcodeInstruction::Br { args, labels } => {
+                    if let LatticeValue::Constant(Value::Bool(cond)) = state.get_value(&args[0]) {
+                        let taken = if cond { &labels[0] } else { &labels[1] };
+                        *opcode_inst = OpcodeInstruction::Jmp {
+                            labels: vec![taken.clone()],
+                        };
+                        changed = true;
+                    }
+                }
+                // phis only keep args coming in through executable edges
+                OpcodeInstruction::Phi { args, labels, .. } => {
+                    let before = labels.len();
+                    let mut kept_args: Vec<String> = Vec::new();
+                    let mut kept_labels: Vec<String> = Vec::new();
+                    for (arg, label) in args.iter().zip(labels.iter()) {
+                        if state.is_edge_executable(label, bb_idx) {
+                            kept_args.push(arg.clone());
+                            kept_labels.push(label.clone());
+                        }
+                    }
+                    changed |= kept_labels.len() != before;
+                    *args = kept_args;
+                    *labels = kept_labels;
+                }
+                _ => {}
+            }
+
+            // pure definitions of constants get replaced by the constant itself
+            if let Some(LatticeValue::Constant(value)) = state.def_values.get(&(bb_idx, inst_idx)) {
+                let is_const: bool = matches!(opcode_inst, OpcodeInstruction::Const { .. });
+                let is_pure: bool = Instruction::Opcode(opcode_inst.clone()).has_no_side_effects();
+                if let (false, true, Some(dest), Some(typ)) = (
+                    is_const,
+                    is_pure,
+                    opcode_inst.get_dest(),
+                    opcode_inst.get_type(),
+                ) {
+                    *opcode_inst = OpcodeInstruction::Const {
+                        dest,
+                        typ,
+                        value: value.clone(),
+                    };
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    // bbs never reached are dropped altogether
+    let num_bbs = bbs.len();
+    let reachable_bbs: Vec<BasicBlock> = bbs
+        .into_iter()
+        .enumerate()
+        .filter(|(bb_idx, _)| state.executable_bbs.contains(bb_idx))
+        .map(|(_, bb)| bb)
+        .collect();
+    changed |= reachable_bbs.len() != num_bbs;
+
+    if changed {
+        function.update(reachable_bbs);
+    }
+
+    changed
+}
+
+pub fn sccp_pass(program: &mut Program) -> bool {
+    let mut changed: bool = false;
+
+    for function in program.functions.iter_mut() {
+        changed |= sccp_fn(function);
+    }
+
+    changed
+}
@@ -0,0 +1,261 @@
+// ssa form verification
+//
+// checks that
+// 1. every variable is defined exactly once (function arguments count as definitions)
+// 2. every use is dominated by its definition, phi args have to dominate the end of the
+//    predecessor they name rather than the phi itself
+// 3. phis sit at the top of their bb and name every predecessor in `in_bb_indices` exactly once
+use crate::ast;
+use crate::dom;
+use ast::*;
+use dom::*;
+use std::collections::{HashMap, HashSet};
+
+// where a variable is defined
+#[derive(Clone, Copy)]
+enum DefSite {
+    Argument,
+    Inst { bb_idx: usize, inst_idx: usize },
+}
+
+fn bb_name(bb: &BasicBlock, bb_idx: usize) -> String {
+    match bb.get_label() {
+        Some(label) => format!(".{}", label),
+        None => format!("<bb {}>", bb_idx),
+    }
+}
+
+// whether the definition is available right after instruction `inst_idx` of bb `bb_idx`,
+// an `inst_idx` of `None` means the end of the bb
+fn def_dominates(
+    def: DefSite,
+    bb_idx: usize,
+    inst_idx: Option<usize>,
+    dom_context: &DomContext,
+) -> bool {
+    match def {
+        DefSite::Argument => true,
+        DefSite::Inst {
+            bb_idx: def_bb_idx,
+            inst_idx: def_inst_idx,
+        } => {
+            if def_bb_idx == bb_idx {
+                match inst_idx {
+                    Some(inst_idx) => def_inst_idx < inst_idx,
+                    None => true,
+                }
+            } else {
+                dom_context.bbs[bb_idx].dominators.contains(&def_bb_idx)
+            }
+        }
+    }
+}
+
+// verify a function is in ssa form, returns a description of every violation found
+pub fn verify_ssa(function: &Function) -> Vec<String> {
+    let mut errors: Vec<String> = Vec::new();
+    let bbs = function.get_basic_blocks();
+    let dom_context = get_dom_context(&bbs);
+
+    let mut label_to_bb: HashMap<String, usize> = HashMap::new();
+    for (bb_idx, bb) in bbs.iter().enumerate() {
+        if let Some(label) = bb.get_label() {
+            label_to_bb.insert(label, bb_idx);
+        }
+    }
+
+    // single definition
+    let mut defs: HashMap<String, DefSite> = HashMap::new();
+    // no single definition to check dominance against for these
+    let mut redefined: HashSet<String> = HashSet::new();
+    if let Some(fn_args) = &function.args {
+        for arg in fn_args.iter() {
+            defs.insert(arg.name.clone(), DefSite::Argument);
+        }
+    }
+    for (bb_idx, bb) in bbs.iter().enumerate() {
+        for (inst_idx, inst) in bb.instrs.iter().enumerate() {
+            if let Instruction::Opcode(opcode_inst) = inst {
+                if let Some(dest) = opcode_inst.get_dest() {
+                    let def = DefSite::Inst { bb_idx, inst_idx };
+                    if defs.insert(dest.clone(), def).is_some() && redefined.insert(dest.clone()) {
+                        errors.push(format!(
+                            "@{}, {}: variable `{}` is defined more than once",
+                            function.name,
+                            bb_name(bb, bb_idx),
+                            dest
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    for (bb_idx, bb) in bbs.iter().enumerate() {
+        // unreachable bbs aren't dominated by anything, nothing to check there
+        if dom_context.bbs[bb_idx].dominators.is_empty() {
+            continue;
+        }
+
+        let mut pred_labels: HashSet<String> = HashSet::new();
+        for pred_idx in bb.in_bb_indices.iter() {
+            match bbs[*pred_idx].get_label() {
+                Some(label) => {
+                    pred_labels.insert(label);
+                }
+                None => {
+                    if bb.instrs.iter().any(|inst| inst.is_phi()) {
+                        errors.push(format!(
+                            "@{}, {}: predecessor {} has no label for phis to refer to",
+                            function.name,
+                            bb_name(bb, bb_idx),
+                            bb_name(&bbs[*pred_idx], *pred_idx)
+                        ));
+                    }
+                }
+            }
+        }
+
+        let mut seen_non_phi: bool = false;
+        for (inst_idx, inst) in bb.instrs.iter().enumerate() {
+            match inst {
+                Instruction::Opcode(OpcodeInstruction::Phi {
+                    args, dest, labels, ..
+                }) => {
+                    if seen_non_phi {
+                        errors.push(format!(
+                            "@{}, {}: phi for `{}` comes after a non-phi instruction",
+                            function.name,
+                            bb_name(bb, bb_idx),
+                            dest
+                        ));
+                    }
+                    if args.len() != labels.len() {
+                        errors.push(format!(
+                            "@{}, {}: phi for `{}` has {} args but {} labels",
+                            function.name,
+                            bb_name(bb, bb_idx),
+                            dest,
+                            args.len(),
+                            labels.len()
+                        ));
+                    }
+                    let phi_labels: HashSet<String> = labels.iter().cloned().collect();
+                    if phi_labels.len() != labels.len() {
+                        errors.push(format!(
+                            "@{}, {}: phi for `{}` names a predecessor more than once",
+                            function.name,
+                            bb_name(bb, bb_idx),
+                            dest
+                        ));
+                    }
+                    let mut missing: Vec<&String> = pred_labels.difference(&phi_labels).collect();
+                    let mut extra: Vec<&String> = phi_labels.difference(&pred_labels).collect();
+                    missing.sort();
+                    extra.sort();
+                    for label in missing {
+                        errors.push(format!(
+                            "@{}, {}: phi for `{}` has no arg for predecessor .{}",
+                            function.name,
+                            bb_name(bb, bb_idx),
+                            dest,
+                            label
+                        ));
+                    }
+                    for label in extra {
+                        errors.push(format!(
+                            "@{}, {}: phi for `{}` names .{}, which is not a predecessor",
+                            function.name,
+                            bb_name(bb, bb_idx),
+                            dest,
+                            label
+                        ));
+                    }
+
+                    // a phi arg is used at the end of the predecessor it comes from
+                    for (arg, label) in args.iter().zip(labels.iter()) {
+                        if arg == UNDEFINED_VAR || redefined.contains(arg) {
+                            continue;
+                        }
+                        let pred_idx = match label_to_bb.get(label) {
+                            Some(pred_idx) if bb.in_bb_indices.contains(pred_idx) => *pred_idx,
+                            _ => continue, // already reported above
+                        };
+                        if dom_context.bbs[pred_idx].dominators.is_empty() {
+                            continue; // never comes from an unreachable predecessor
+                        }
+                        match defs.get(arg) {
+                            None => errors.push(format!(
+                                "@{}, {}: variable `{}` is used by phi for `{}` but never defined",
+                                function.name,
+                                bb_name(bb, bb_idx),
+                                arg,
+                                dest
+                            )),
+                            Some(def) => {
+                                if !def_dominates(*def, pred_idx, None, &dom_context) {
+                                    errors.push(format!(
+                                        "@{}, {}: variable `{}` used by phi for `{}` is not defined on every path to .{}",
+                                        function.name,
+                                        bb_name(bb, bb_idx),
+                                        arg,
+                                        dest,
+                                        label
+                                    ));
+                                }
+                            }
+                        }
+                    }
+                }
+                Instruction::Opcode(opcode_inst) => {
+                    seen_non_phi = true;
+                    for arg in opcode_inst.get_var_use_list() {
+                        if redefined.contains(&arg) {
+                            continue;
+                        }
+                        match defs.get(&arg) {
+                            None => errors.push(format!(
+                                "@{}, {}: variable `{}` is used but never defined",
+                                function.name,
+                                bb_name(bb, bb_idx),
+                                arg
+                            )),
+                            Some(def) => {
+                                if !def_dominates(*def, bb_idx, Some(inst_idx), &dom_context) {
+                                    errors.push(format!(
+                                        "@{}, {}: use of variable `{}` is not dominated by its definition",
+                                        function.name,
+                                        bb_name(bb, bb_idx),
+                                        arg
+                                    ));
+                                }
+                            }
+                        }
+                    }
+                }
+                Instruction::Label { .. } => {}
+                Instruction::Nop { .. } => seen_non_phi = true,
+            }
+        }
+    }
+
+    errors
+}
+
+// doesn't change anything, blows up the program if any function isn't in valid ssa form.
+// can be dropped anywhere in the pass pipeline to check the passes before it.
+pub fn verify_ssa_pass(program: &mut Program) -> bool {
+    let mut errors: Vec<String> = Vec::new();
+    for function in program.functions.iter() {
+        errors.extend(verify_ssa(function));
+    }
+
+    if !errors.is_empty() {
+        for error in errors.iter() {
+            eprintln!("ssa violation in {}", error);
+        }
+        panic!("program is not in ssa form, {} violation(s)", errors.len());
+    }
+
+    false
+}
@@ -0,0 +1,51 @@
+// scoped symbol table, for walks over trees of bbs that have to forget what a subtree added
+// once they move on to its sibling
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+// hash map whose insertions can be rolled back scope by scope
+pub struct ScopedTable<K, V> {
+    table: HashMap<K, V>,
+    undo_log: Vec<(K, Option<V>)>, // key and what it mapped to before the insertion
+    scope_starts: Vec<usize>,      // undo log length when each open scope was pushed
+}
+
+impl<K: Hash + Eq + Clone, V> ScopedTable<K, V> {
+    pub fn new() -> ScopedTable<K, V> {
+        ScopedTable {
+            table: HashMap::new(),
+            undo_log: Vec::new(),
+            scope_starts: Vec::new(),
+        }
+    }
+
+    pub fn push_scope(&mut self) {
+        self.scope_starts.push(self.undo_log.len());
+    }
+
+    // undo every insertion made since the matching `push_scope`
+    pub fn pop_scope(&mut self) {
+        let start = self.scope_starts.pop().unwrap();
+        while self.undo_log.len() > start {
+            let (key, old_value) = self.undo_log.pop().unwrap();
+            match old_value {
+                Some(old_value) => self.table.insert(key, old_value),
+                None => self.table.remove(&key),
+            };
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        let old_value = self.table.insert(key.clone(), value);
+        self.undo_log.push((key, old_value));
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.table.get(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.table.iter()
+    }
+}